@@ -0,0 +1,291 @@
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use reqwest::{Response, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER, USER_AGENT};
+use serde::de::DeserializeOwned;
+use tracing::warn;
+
+use utils::{client::ClientInfo, server::{ApiVersion, Discovery, Endpoint, GetClientInfoResponse, PostClientInfoResponse, Problem, ResponseMessage}, AppInfo};
+use utils::version::{self, CLIENT_VERSION_HEADER, Compatibility, client_user_agent};
+
+/// The API versions this client speaks, the preferred first.
+const SUPPORTED_API_VERSIONS: &[&str] = &["v1"];
+
+/// The range of the server versions this client works with.
+const SUPPORTED_SERVER_VERSIONS: &str = "^0.1";
+
+/// The version of this client.
+const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The errors of calling the API.
+#[derive(Debug)]
+pub(crate) enum ApiError {
+    /// The server cannot be reached, or its answer cannot be read.
+    Network(reqwest::Error),
+    /// The server answered with problem details.
+    Problem(Box<Problem>),
+    /// The server answered an error without problem details, such as from a proxy.
+    Status(StatusCode),
+    /// The answer is not what the API describes.
+    Decode(serde_json::Error),
+    /// The server and this client cannot work together, so retrying does not help.
+    Incompatible(String),
+    /// Over a rate limit of the server. Retry after the duration if it is told.
+    RateLimited(Option<Duration>),
+    /// Locked out after wrong passwords. Retry after the duration if it is told.
+    LockedOut(Option<Duration>),
+}
+
+/// What kind of failure an error is, to decide whether to retry.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum ErrorClass {
+    /// The server cannot be reached.
+    Network,
+    /// The server refused the request (4xx).
+    Client,
+    /// The server failed (5xx), or answered what cannot be read.
+    Server,
+}
+
+impl ApiError {
+    pub(crate) fn class(&self) -> ErrorClass {
+        let of_status = |status: u16| if status >= 500 { ErrorClass::Server } else { ErrorClass::Client };
+        match self {
+            ApiError::Network(_) => ErrorClass::Network,
+            ApiError::Problem(problem) => of_status(problem.status),
+            ApiError::Status(status) => of_status(status.as_u16()),
+            ApiError::Decode(_) => ErrorClass::Server,
+            ApiError::Incompatible(_) | ApiError::RateLimited(_) | ApiError::LockedOut(_) => ErrorClass::Client,
+        }
+    }
+
+    /// Whether retrying the same request may succeed. Only a request the server refuses
+    /// for what it is fails the same way again: a wrong password, an address not allowed,
+    /// an invalid request or an incompatible client. The others, even a bare `4xx` from
+    /// a proxy, may pass later.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Incompatible(_) => false,
+            ApiError::Problem(problem) => !matches!(
+                problem.code,
+                ResponseMessage::InvalidPassword | ResponseMessage::AddressNotAllowed
+                    | ResponseMessage::InvalidRequest | ResponseMessage::IncompatibleVersion
+            ),
+            _ => true,
+        }
+    }
+
+    /// The code of the problem the server answered, if it did.
+    pub(crate) fn code(&self) -> Option<ResponseMessage> {
+        match self {
+            ApiError::Problem(problem) => Some(problem.code),
+            ApiError::RateLimited(_) => Some(ResponseMessage::TooManyRequests),
+            ApiError::LockedOut(_) => Some(ResponseMessage::LockedOut),
+            _ => None,
+        }
+    }
+
+    /// How long the server asks to wait before retrying, if it does.
+    pub(crate) fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::RateLimited(retry_after) | ApiError::LockedOut(retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Network(e) => write!(f, "Cannot reach the server: {}", e),
+            ApiError::Problem(problem) => write!(f, "{}", problem),
+            ApiError::Status(status) => write!(f, "The server answered {}.", status),
+            ApiError::Decode(e) => write!(f, "Cannot read the answer of the server: {}", e),
+            ApiError::Incompatible(reason) => write!(f, "{}", reason),
+            ApiError::RateLimited(Some(retry_after)) => write!(f, "Too many requests, the server asks to retry after {} second(s).", retry_after.as_secs()),
+            ApiError::RateLimited(None) => write!(f, "Too many requests."),
+            ApiError::LockedOut(Some(retry_after)) => write!(
+                f, "Locked out after too many wrong passwords, for {} more second(s). Check the password.", retry_after.as_secs()
+            ),
+            ApiError::LockedOut(None) => write!(f, "Locked out after too many wrong passwords. Check the password."),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Network(e)
+    }
+}
+
+/// The API of a server, at the version picked from its discovery document.
+#[derive(Clone)]
+pub(crate) struct Api {
+    http: reqwest::Client,
+    /// The API root, without a trailing slash.
+    api_url: String,
+    version: ApiVersion,
+    server: AppInfo,
+}
+
+impl Api {
+    /// Read the discovery document at the API root, and pick the newest version both sides speak.
+    /// A server from before the versions has no discovery document, and is spoken to by the old paths.
+    /// Fails with `Incompatible` if the server is out of the supported versions.
+    pub(crate) async fn discover(api_url: &str) -> Result<Self, ApiError> {
+        /* Tell the server who we are, so it can refuse an unsupported version. */
+        let mut headers = HeaderMap::new();
+        let user_agent = HeaderValue::from_str(&client_user_agent(CLIENT_VERSION)).expect("The user agent is a valid header value.");
+        headers.insert(USER_AGENT, user_agent);
+        headers.insert(CLIENT_VERSION_HEADER, HeaderValue::from_static(CLIENT_VERSION));
+        let http = reqwest::Client::builder().default_headers(headers).build()?;
+        let api_url = api_url.trim_end_matches('/').to_owned();
+        let resp = http.get(&api_url).send().await?;
+        let mut api = if resp.status() == StatusCode::NOT_FOUND {
+            Self { http, api_url, version: unversioned(), server: AppInfo::new("", "") }
+        }
+        else {
+            let discovery: Discovery = read(resp).await?;
+            let version = match discovery.pick(SUPPORTED_API_VERSIONS) {
+                Some(version) => version.clone(),
+                None => return Err(ApiError::Incompatible(format!(
+                    "The server offers the API versions {:?}, but this client speaks {:?}.",
+                    discovery.versions.iter().map(|v| &v.version).collect::<Vec<_>>(), SUPPORTED_API_VERSIONS
+                ))),
+            };
+            Self { http, api_url, version, server: discovery.app }
+        };
+        if api.server.version.is_empty() {
+            api.server = api.get_server_info().await?;
+        }
+        api.check_server_version()?;
+        Ok(api)
+    }
+
+    /// Refuse a server out of the supported versions, and warn about a newer one.
+    fn check_server_version(&self) -> Result<(), ApiError> {
+        match version::compare(CLIENT_VERSION, &self.server.version, SUPPORTED_SERVER_VERSIONS) {
+            Ok(Compatibility::Compatible) => Ok(()),
+            Ok(Compatibility::Newer) => {
+                warn!(server_version = %self.server.version, client_version = CLIENT_VERSION, "The server is newer than this client. Consider upgrading the client.");
+                Ok(())
+            },
+            Ok(Compatibility::Incompatible) => Err(ApiError::Incompatible(format!(
+                "The server version {} is not supported by this client {} (supported: {}). Upgrade the older one.",
+                self.server.version, CLIENT_VERSION, SUPPORTED_SERVER_VERSIONS
+            ))),
+            Err(e) => Err(ApiError::Incompatible(format!("Cannot compare the server version: {}", e))),
+        }
+    }
+
+    /// The server app information.
+    pub(crate) fn server(&self) -> &AppInfo {
+        &self.server
+    }
+
+    /// The API version picked.
+    pub(crate) fn version(&self) -> &ApiVersion {
+        &self.version
+    }
+
+    /// The URL of an endpoint, if the server offers it.
+    fn url(&self, endpoint: Endpoint) -> Result<String, ApiError> {
+        match self.version.endpoint(endpoint) {
+            Some(path) => Ok(format!("{}/{}", self.api_url, path.trim_start_matches('/'))),
+            None => Err(ApiError::Incompatible(format!(
+                "The server does not offer the {:?} endpoint in API {}.", endpoint, self.version.version
+            ))),
+        }
+    }
+
+    /// Send to server a get request, and take back an `AppInfo` response.
+    pub(crate) async fn get_server_info(&self) -> Result<AppInfo, ApiError> {
+        /* Get server response. */
+        let resp = self.http.get(self.url(Endpoint::ServerInfo)?).send().await?;
+        /* Parse the server response into an `AppInfo` struct. */
+        read(resp).await
+    }
+
+    /// Send to server a post request, and take back an `PostClientInfoResponse` response.
+    pub(crate) async fn post_my_info(&self, info: &ClientInfo) -> Result<PostClientInfoResponse, ApiError> {
+        /* Build up a header. */
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+
+        /* Post my information, then get response from the server. */
+        let resp = self.http.post(self.url(Endpoint::PostClientInfo)?).headers(headers).json(&info).send().await?;
+
+        /* Parse the server response into a `PostClientInfoResponse` struct. */
+        read(resp).await
+    }
+
+    /// Ask the server to take my information offline at once.
+    pub(crate) async fn deregister_my_info(&self, info: &ClientInfo) -> Result<PostClientInfoResponse, ApiError> {
+        let resp = self.http.post(self.url(Endpoint::DeregisterClientInfo)?).json(&info).send().await?;
+        read(resp).await
+    }
+
+    /// Send to server a get request, and take back a `GetClientInfoResponse` response.
+    pub(crate) async fn get_client_info(&self, account: &str, passwd: &Option<String>) -> Result<GetClientInfoResponse, ApiError> {
+        let mut query = vec![("account", account)];
+        if let Some(p) = passwd {
+            query.push(("passwd", p));
+        }
+        let resp = self.http.get(self.url(Endpoint::GetClientInfo)?).query(&query).send().await?;
+        read(resp).await
+    }
+}
+
+/// Parse a response. An error is read from its problem details, a refusal of this
+/// client version is `Incompatible`, and a refusal to retry later is `RateLimited` or `LockedOut`.
+async fn read<T: DeserializeOwned>(resp: Response) -> Result<T, ApiError> {
+    let status = resp.status();
+    /* Only the seconds form, the date form is not sent by the server. */
+    let retry_after = resp.headers().get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs);
+    let body = resp.bytes().await?;
+    if status.is_success() {
+        return serde_json::from_slice(&body).map_err(ApiError::Decode);
+    }
+    match serde_json::from_slice::<Problem>(&body) {
+        Ok(problem) if problem.code == ResponseMessage::IncompatibleVersion => {
+            Err(ApiError::Incompatible(format!("{} Upgrade the older one.", problem.detail)))
+        },
+        Ok(problem) if problem.code == ResponseMessage::LockedOut => Err(ApiError::LockedOut(retry_after)),
+        _ if status == StatusCode::TOO_MANY_REQUESTS => Err(ApiError::RateLimited(retry_after)),
+        Ok(problem) => Err(ApiError::Problem(Box::new(problem))),
+        Err(_) => Err(ApiError::Status(status)),
+    }
+}
+
+/// The paths of a server from before the versions. Its capabilities are unknown.
+fn unversioned() -> ApiVersion {
+    ApiVersion {
+        version: "unversioned".to_owned(),
+        endpoints: [
+            (Endpoint::ServerInfo, "server"),
+            (Endpoint::GetClientInfo, "client/get"),
+            (Endpoint::PostClientInfo, "client/post"),
+            (Endpoint::DeregisterClientInfo, "client/deregister"),
+        ].into_iter().map(|(e, path)| (e, path.to_owned())).collect(),
+        capabilities: vec![],
+    }
+}
+
+#[test]
+fn test_is_retryable() {
+    let problem = |code| ApiError::Problem(Box::new(Problem::new(code, 403, "")));
+    assert!(!problem(ResponseMessage::InvalidPassword).is_retryable());
+    assert!(!problem(ResponseMessage::AddressNotAllowed).is_retryable());
+    assert!(!ApiError::Incompatible("".to_owned()).is_retryable());
+    /* Not about the request itself, so it may pass later. */
+    assert!(problem(ResponseMessage::NotFound).is_retryable());
+    assert!(problem(ResponseMessage::DatabaseError).is_retryable());
+    assert!(ApiError::Status(StatusCode::FORBIDDEN).is_retryable());
+    assert!(ApiError::LockedOut(None).is_retryable());
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+
+use utils::config::{self, FieldError};
+
+/// Default seconds before the first retry.
+const DEFAULT_BASE_DELAY: u64 = 1;
+
+/// Default longest seconds between two retries. Within the default lifetime of a record,
+/// so a short outage does not take it offline.
+const DEFAULT_MAX_DELAY: u64 = 60;
+
+/// The bounds of the delays in the config. Seconds.
+const DELAY_RANGE: std::ops::RangeInclusive<u64> = 1..=86400;
+
+/// The `[retry]` section of the client config.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct RetryConfig {
+    /// Seconds before the first retry, doubled by every failure after it.
+    pub(crate) base_delay: u64,
+    /// The longest seconds between two retries.
+    pub(crate) max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self { base_delay: DEFAULT_BASE_DELAY, max_delay: DEFAULT_MAX_DELAY }
+    }
+}
+
+impl RetryConfig {
+    pub(crate) fn validate(&self, errors: &mut Vec<FieldError>) {
+        errors.extend(config::check_range("retry.base_delay", self.base_delay, DELAY_RANGE));
+        errors.extend(config::check_range("retry.max_delay", self.max_delay, DELAY_RANGE));
+        if self.max_delay < self.base_delay {
+            errors.push(
+                FieldError::new("retry.max_delay", format!("is shorter than `retry.base_delay` ({})", self.base_delay))
+                    .suggest("use a value not less than `retry.base_delay`")
+            );
+        }
+    }
+}
+
+/// The delays between the retries of one kind of request: exponential with full jitter,
+/// up to the cap. Counts the consecutive failures until a success.
+pub(crate) struct Backoff {
+    config: RetryConfig,
+    failures: u32,
+    /// When the consecutive failures began.
+    since: Option<Instant>,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: RetryConfig) -> Self {
+        Self { config, failures: 0, since: None }
+    }
+
+    /// Count a failure, and return how long to wait before retrying. A wait the server
+    /// asked for is taken, up to `max_delay`.
+    pub(crate) fn fail(&mut self, retry_after: Option<Duration>) -> Duration {
+        self.since.get_or_insert_with(Instant::now);
+        let ceiling = ceiling(&self.config, self.failures);
+        self.failures = self.failures.saturating_add(1);
+        match retry_after {
+            Some(retry_after) => retry_after.min(Duration::from_secs(self.config.max_delay)),
+            None => ceiling.mul_f64(rand::thread_rng().gen::<f64>()),
+        }
+    }
+
+    /// The consecutive failures so far.
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Forget the failures after a success. Return how many there were and for how long, if any.
+    pub(crate) fn succeed(&mut self) -> Option<(u32, Duration)> {
+        let failures = std::mem::take(&mut self.failures);
+        self.since.take().map(|since| (failures, since.elapsed()))
+    }
+}
+
+/// The longest wait after `failures` failures: the base delay doubled, up to the cap.
+fn ceiling(config: &RetryConfig, failures: u32) -> Duration {
+    let doubled = config.base_delay.saturating_mul(1 << failures.min(32));
+    Duration::from_secs(doubled.min(config.max_delay))
+}
+
+#[test]
+fn test_backoff() {
+    let config = RetryConfig { base_delay: 2, max_delay: 60 };
+    assert_eq!(ceiling(&config, 0), Duration::from_secs(2));
+    assert_eq!(ceiling(&config, 3), Duration::from_secs(16));
+    assert_eq!(ceiling(&config, 100), Duration::from_secs(60));
+
+    let mut backoff = Backoff::new(config);
+    for failures in 0..10 {
+        assert!(backoff.fail(None) <= ceiling(&backoff.config, failures));
+    }
+    assert_eq!(backoff.fail(Some(Duration::from_secs(7))), Duration::from_secs(7));
+    /* A server asking for too long is not waited for longer than the cap. */
+    assert_eq!(backoff.fail(Some(Duration::from_secs(3600))), Duration::from_secs(60));
+    assert_eq!(backoff.failures(), 12);
+    assert_eq!(backoff.succeed().map(|(failures, _)| failures), Some(12));
+    assert_eq!(backoff.failures(), 0);
+    assert_eq!(backoff.succeed(), None);
+}
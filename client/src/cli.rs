@@ -0,0 +1,101 @@
+use clap::{Parser, Subcommand};
+use utils::logging::LogFormat;
+
+use crate::output::{AddressFilter, Format};
+
+/// The client of Here. Posts the IPs of this device to the server, and looks up the others.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub(crate) struct Cli {
+    /// The local config file. `/etc/here/client.toml` and `~/.config/here/client.toml` are read before it.
+    #[arg(long, global = true, default_value = crate::DEFAULT_CONFIG_PATH)]
+    pub(crate) config: String,
+
+    /// Override the account in the config.
+    #[arg(long, global = true)]
+    pub(crate) account: Option<String>,
+
+    /// Override the password in the config. The flag is seen by the other users in the process
+    /// list, and kept in the shell history; the environment variable or `--passwd-stdin` is not.
+    #[arg(long, global = true, env = "HERE_PASSWD", hide_env_values = true)]
+    pub(crate) passwd: Option<String>,
+
+    /// Read the password overriding the config from the first line of the standard input.
+    #[arg(long, global = true)]
+    pub(crate) passwd_stdin: bool,
+
+    /// Override the API URL in the config. Example: http://localhost:8080/here
+    #[arg(long, global = true)]
+    pub(crate) api_url: Option<String>,
+
+    /// The log level, or a filter such as `warn,server=debug`.
+    #[arg(long, global = true, env = "HERE_LOG_LEVEL", default_value = "info")]
+    pub(crate) log_level: String,
+
+    /// The log format: `text` or `json`.
+    #[arg(long, global = true, env = "HERE_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    pub(crate) log_format: LogFormat,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Post the IPs forever, once per lifetime. The default.
+    Run,
+    /// Post the IPs once, then exit.
+    Once,
+    /// Look up the addresses of an account.
+    Query {
+        /// The account to look up.
+        #[arg(value_name = "ACCOUNT")]
+        target: String,
+        /// The password of the account to look up. The environment variable or
+        /// `--target-passwd-stdin` keeps it out of the process list.
+        #[arg(long, env = "HERE_TARGET_PASSWD", hide_env_values = true)]
+        target_passwd: Option<String>,
+        /// Read the password of the account to look up from the first line of the standard input.
+        #[arg(long)]
+        target_passwd_stdin: bool,
+        /// Also print the device details: id, presence and the observed address. Plain format only.
+        #[arg(long)]
+        device: bool,
+        /// The output format.
+        #[arg(long, value_enum, default_value_t = Format::Plain)]
+        format: Format,
+        /// Only the IPv4 addresses.
+        #[arg(short = '4', conflicts_with = "ipv6")]
+        ipv4: bool,
+        /// Only the IPv6 addresses.
+        #[arg(short = '6')]
+        ipv6: bool,
+        /// Only the address the server observed.
+        #[arg(long)]
+        observed: bool,
+    },
+    /// Print the configured account, and how the server sees it.
+    Whoami,
+    /// Create the local config file from the flags, and ask for the others.
+    Init {
+        /// Overwrite the config file if it exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Load and validate the config, report the problems, then exit.
+    CheckConfig,
+}
+
+impl Command {
+    /// The address filter from the query flags.
+    pub(crate) fn address_filter(&self) -> AddressFilter {
+        match self {
+            Command::Query { ipv4, ipv6, observed, .. } => AddressFilter {
+                ipv4_only: *ipv4,
+                ipv6_only: *ipv6,
+                observed_only: *observed,
+            },
+            _ => AddressFilter::default(),
+        }
+    }
+}
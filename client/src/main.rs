@@ -1,55 +1,233 @@
-use std::io::Read;
-use std::{thread, time};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
+use clap::Parser;
 use serde_derive::{Deserialize, Serialize};
-use reqwest::header::HeaderMap;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
 
-use utils::{client::ClientInfo, server::PostClientInfoResponse, AppInfo};
+use utils::client::ClientInfo;
+use utils::config::{self, ConfigLoader, FieldError, Validate};
+use utils::logging;
+
+use crate::api::Api;
+use crate::backoff::{Backoff, RetryConfig};
+use crate::servers::{ApiUrls, ServerMode, Servers};
+use crate::cli::{Cli, Command};
+use crate::output::{AddressFilter, Format};
 
 mod info;
 
-/// The seconds of sleeping.
-const SLEEP_SECONDS: f64 = 1.0;
+/// About the requests to the RESTful API server.
+mod api;
+
+/// About the cmdline arguments.
+mod cli;
+
+/// About printing the query results.
+mod output;
+
+/// About updating the endpoints of WireGuard peers.
+mod wireguard;
+
+/// About waiting between the retries.
+mod backoff;
+
+/// About using several servers.
+mod servers;
 
 /// Default config file put at this path.
 const DEFAULT_CONFIG_PATH: &str = "./client.conf.toml";
 
+/// The base name of the config files in the config directories.
+const CONFIG_NAME: &str = "client";
+
 #[derive(Serialize, Deserialize)]
 struct Config {
+    /// Not needed to look up the others.
+    #[serde(default)]
     account: String,
     passwd: Option<String>,
-    api_url: String,
+    /// One API URL, or a list of them for several servers.
+    api_url: ApiUrls,
+    /// How to post to several servers.
+    #[serde(default)]
+    server_mode: ServerMode,
+    /// Keep the endpoints of these WireGuard peers up to date.
+    #[serde(default)]
+    wireguard: Option<wireguard::WireGuardConfig>,
+    /// Take my information offline at once when `run` quits, instead of letting it expire.
+    #[serde(default)]
+    deregister_on_exit: bool,
+    /// How long to wait between the retries of `run`.
+    #[serde(default)]
+    retry: RetryConfig,
+}
+
+impl Validate for Config {
+    const KEYS: &'static [&'static str] = &["account", "passwd", "api_url", "server_mode", "wireguard", "deregister_on_exit", "retry"];
+
+    fn validate(&self, errors: &mut Vec<FieldError>) {
+        /* Empty if looking up the others only. */
+        if !self.account.is_empty() {
+            errors.extend(config::check_account("account", &self.account));
+        }
+        self.api_url.validate(errors);
+        if let Some(wg_config) = &self.wireguard {
+            wg_config.validate(errors);
+        }
+        self.retry.validate(errors);
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    /* Set the Ctrl-C handler. */
-    ctrlc::set_handler(|| {
-        eprintln!("Quit.");
-        std::process::exit(0);
-    }).expect("Cannot set Ctrl-C handler.");
-
-    /* Load config from the file. */
-    println!("Loading config...");
-    let config = get_config(DEFAULT_CONFIG_PATH).expect("Cannot load config.");
-
-    /* Test network linking, and the server app version. */
-    let server_info = loop {
-        match get_server_info(&format!("{}/server", config.api_url)).await {
-            Ok(info) => {
-                println!("Listening to server response of app information...");
-                break info;
+    /* Parse the cmdline arguments. */
+    let mut cli = Cli::parse();
+    if let Err(e) = logging::init(&cli.log_level, cli.log_format) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if cli.passwd_stdin {
+        match read_passwd_line() {
+            Ok(passwd) => cli.passwd = Some(passwd),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             },
-            Err(_) => {
-                /* Sleep a second. */
-                eprintln!("Cannot get the app information from the server yet.\nSleeping... Retry after {} second(s).", SLEEP_SECONDS);
-                thread::sleep(time::Duration::from_secs_f64(SLEEP_SECONDS));
-                /* Continue to post. */
-                continue;
+        }
+    }
+
+    if let Err(e) = dispatch(&cli).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Run the subcommand.
+async fn dispatch(cli: &Cli) -> Result<(), anyhow::Error> {
+    match &cli.command {
+        None | Some(Command::Run) => run(load_config(cli)?).await,
+        Some(Command::Once) => once(&load_config(cli)?).await,
+        Some(command @ Command::Query { target, target_passwd, target_passwd_stdin, device, format, .. }) => {
+            if cli.passwd_stdin && *target_passwd_stdin {
+                anyhow::bail!("Only one password can be read from the standard input.");
+            }
+            let passwd = if *target_passwd_stdin { Some(read_passwd_line()?) } else { target_passwd.clone() };
+            let config = load_config(cli)?;
+            let mut servers = Servers::new(&config.api_url, config.server_mode);
+            query(&mut servers, target, &passwd, *format, command.address_filter(), *device).await
+        },
+        Some(Command::Whoami) => whoami(&load_config(cli)?).await,
+        Some(Command::Init { force }) => init(cli, *force),
+        Some(Command::CheckConfig) => check_config(cli),
+    }
+}
+
+/// Read a password from the first line of the standard input.
+fn read_passwd_line() -> Result<String, anyhow::Error> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let passwd = line.trim_end_matches(['\r', '\n']);
+    if passwd.is_empty() {
+        anyhow::bail!("No password is given in the standard input.");
+    }
+    Ok(passwd.to_owned())
+}
+
+/// SIGINT and SIGTERM. Listened to from the creation on, so one coming during a post is kept.
+/// Without the Unix signals, only Ctrl-C is listened to, while sleeping.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: Signal,
+    #[cfg(unix)]
+    terminate: Signal,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {})
+    }
+
+    /// Wait for a signal.
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.interrupt.recv() => {},
+            _ = self.terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        if tokio::signal::ctrl_c().await.is_err() {
+            /* Nothing to wait for, so only the sleep ends. */
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Sleep for `duration`. Return `true` if a signal comes first.
+    async fn sleep(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => false,
+            _ = self.recv() => true,
+        }
+    }
+}
+
+/// Post my information once per lifetime, until SIGINT or SIGTERM.
+/// A post in progress is finished before quitting. Failures are retried with backoff,
+/// unless retrying cannot help, such as for an incompatible server or a refused account.
+async fn run(config: Config) -> Result<(), anyhow::Error> {
+    let mut signals = Signals::new()?;
+    let mut backoff = Backoff::new(config.retry.clone());
+    /* Shared with the WireGuard updater, so both go to the server in use and see how the servers are doing. */
+    let servers = Arc::new(Mutex::new(Servers::new(&config.api_url, config.server_mode)));
+
+    /* Test network linking, pick the API version, and check the server app version. */
+    let api = loop {
+        /* Not locked while sleeping. */
+        let discovered = servers.lock().await.discover().await;
+        match discovered {
+            Ok(api) => {
+                debug!("Listening to server response of app information...");
+                recovered(&mut backoff, "Reached the server");
+                break api;
+            },
+            Err(e) if !e.is_retryable() => return Err(e.into()),
+            Err(e) => {
+                /* Back off, or wait as long as the server asks. */
+                let delay = backoff.fail(e.retry_after());
+                warn!(
+                    error = %e, class = ?e.class(), failures = backoff.failures(),
+                    "Cannot get the app information from the server yet. Retry after {:.1} second(s).", delay.as_secs_f64()
+                );
+                if signals.sleep(delay).await {
+                    info!("Quit.");
+                    return Ok(());
+                }
             },
         }
     };
-    println!("Got the app information: {}", server_info);
+    info!("Got the app information: {}", api.server());
+    info!("Using API {}.", describe_version(&api));
+
+    /* Update the WireGuard peers in the background, if it is configured. */
+    if let Some(wg_config) = config.wireguard.clone() {
+        tokio::spawn(wireguard::run_updater(servers.clone(), wg_config));
+    }
 
     loop {
         /* Read my IPs. */
@@ -57,101 +235,167 @@ async fn main() {
         /* Build my information. */
         let my_info = ClientInfo::builder(rand::random(), &config.account, &config.passwd).set_ips(&my_ips);
         /* Post my information. */
-        match post_my_info(&format!("{}/client/post", config.api_url), &my_info).await {
-            Ok(resp) => {
+        let posted = servers.lock().await.post_my_info(&my_info).await;
+        let delay = match posted {
+            Ok(posted) => {
                 /* We success to post our information. */
-                let lifetime = resp.lifetime();
-                #[cfg(feature = "debug-printing")] println!("Server response: {:?}", resp);
-                /* Nothing to do. */
-                println!("Successfully posted.\nSleeping... Redo post after {} second(s).", lifetime);
-                thread::sleep(time::Duration::from_secs(lifetime));
+                let lifetime = posted.resp.lifetime();
+                debug!(resp = ?posted.resp, "Server response.");
+                recovered(&mut backoff, "Posted");
                 /* Redo after run out the lifetime. */
-                continue;
+                info!(servers = %posted.accepted.join(", "), "Successfully posted. Redo post after {} second(s).", lifetime);
+                Duration::from_secs(lifetime)
             },
-            Err(_) => {
-                /* Sleep a second. */
-                eprintln!("Cannot post my information.\nSleeping... Retry after {} second(s).", SLEEP_SECONDS);
-                thread::sleep(time::Duration::from_secs_f64(SLEEP_SECONDS));
-                /* Continue to post. */
-                continue;
+            /* Such as the server was replaced by one which refuses this client, or the account is refused. */
+            Err(e) if !e.is_retryable() => return Err(e.into()),
+            Err(e) => {
+                /* Back off, or wait as long as the server asks, then continue to post. */
+                let delay = backoff.fail(e.retry_after());
+                warn!(
+                    error = %e, class = ?e.class(), failures = backoff.failures(),
+                    "Cannot post my information. Retry after {:.1} second(s).", delay.as_secs_f64()
+                );
+                delay
             },
+        };
+        if signals.sleep(delay).await {
+            break;
+        }
+    }
+
+    if config.deregister_on_exit {
+        deregister(&mut *servers.lock().await, &config).await;
+    }
+    info!("Quit.");
+    Ok(())
+}
+
+/// Log how long it failed before a success, if it did.
+fn recovered(backoff: &mut Backoff, what: &str) {
+    if let Some((failures, failing_for)) = backoff.succeed() {
+        info!(failures, "{} after {} consecutive failure(s) over {} second(s).", what, failures, failing_for.as_secs());
+    }
+}
+
+/// The API version picked, and its capabilities.
+fn describe_version(api: &Api) -> String {
+    let version = api.version();
+    let capabilities: Vec<String> = version.capabilities.iter().map(ToString::to_string).collect();
+    format!("{} ({})", version.version, if capabilities.is_empty() { "no capabilities listed".to_owned() } else { capabilities.join(", ") })
+}
+
+/// Take my information offline at once, on every server reached. Failing is fine, it expires anyway.
+async fn deregister(servers: &mut Servers, config: &Config) {
+    let my_info = ClientInfo::builder(0, &config.account, &config.passwd);
+    for (server, result) in servers.deregister_my_info(&my_info).await {
+        match result {
+            Ok(resp) if resp.is_ok() => info!(%server, "Deregistered."),
+            Ok(resp) => error!(%server, "Cannot deregister: {:?}", resp.message()),
+            Err(e) => error!(%server, "Cannot deregister: {}", e),
+        }
+    }
+}
+
+/// Post my information once, and tell which servers took it.
+async fn once(config: &Config) -> Result<(), anyhow::Error> {
+    let my_ips = vec![info::my_ip()?];
+    let my_info = ClientInfo::builder(rand::random(), &config.account, &config.passwd).set_ips(&my_ips);
+    let mut servers = Servers::new(&config.api_url, config.server_mode);
+    let posted = servers.post_my_info(&my_info).await?;
+    debug!(resp = ?posted.resp, "Server response.");
+    if !posted.resp.is_ok() {
+        anyhow::bail!("The server refused the post: {:?}", posted.resp.message());
+    }
+    println!("Successfully posted. The record lives for {} second(s).", posted.resp.lifetime());
+    for (server, health) in servers.health() {
+        match &health.last_error {
+            _ if posted.accepted.iter().any(|s| s == server) => println!("{}: accepted", server),
+            Some(e) => println!("{}: failed: {}", server, e),
+            None => println!("{}: not tried", server),
         }
     }
+    Ok(())
 }
 
-/// Send to server a get request, and take back an `AppInfo` response.
-async fn get_server_info(server_url: &str) -> Result<AppInfo, anyhow::Error> {
-    /* Get server response. */
-    let resp = reqwest::get(server_url).await?;
-    /* Parse the server response into an `AppInfo` struct. */
-    Ok(resp.json().await?)
-}
-
-/// Send to server a post request, and take back an `PostClientInfoResponse` response.
-async fn post_my_info(server_url: &str, info: &ClientInfo) -> Result<PostClientInfoResponse, anyhow::Error> {
-    let client = reqwest::Client::new();
-
-    /* Build up a header. */
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", "application/json".parse()?);
-
-    /* Post my information, then get response from the server. */
-    let resp = client.post(server_url).headers(headers).json(&info).send().await?;
-
-    /* Parse the server response into a `PostClientInfoResponse` struct. */
-    Ok(resp.json().await?)
-}
-
-/// Read the config file at `path`, or create a new one
-/// by default config if the file not exists.
-fn get_config(path: &str) -> Result<Config, anyhow::Error> {
-    use std::fs::File;
-    use std::io::Write;
-
-    use std::io::{stdin, stdout};
-    /* Try to open the config file. Create a new one if it is not exists. */
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => {
-            /* Read config from the cmdline. */
-            print!("Please input the account: ");
-            stdout().flush()?;
-            let mut account = String::new();
-            stdin().read_line(&mut account)?;
-            let account = account.trim().to_owned();
-
-            print!("Please input the password (leave it empty for no password): ");
-            stdout().flush()?;
-            let mut passwd = String::new();
-            stdin().read_line(&mut passwd)?;
-            /* Set no password if leave it blank. */
-            let passwd = if passwd.trim().is_empty() { Some(passwd) } else { None };
-
-            print!("Please input the API URL (example: http://localhost/here): ");
-            stdout().flush()?;
-            let mut api_url = String::new();
-            stdin().read_line(&mut api_url)?;
-            let api_url = api_url.trim().to_owned();
-
-            /* Build a new config by default. */
-            let default_config = Config {
-                account,
-                passwd,
-                api_url,
-            };
-
-            /* Create a file and write contents. */
-            let mut new_file = File::create(path)?;
-            let buf = toml::to_vec(&default_config)?;
-            new_file.write(&buf)?;
-
-            /* Return the default config. */
-            return Ok(default_config);
+/// Look up an account, and print it in `format`.
+async fn query(servers: &mut Servers, account: &str, passwd: &Option<String>, format: Format, filter: AddressFilter, device: bool) -> Result<(), anyhow::Error> {
+    let resp = servers.get_client_info(account, passwd).await?;
+    debug!(?resp, "Server response.");
+    if !resp.is_ok() {
+        anyhow::bail!("Cannot look up the account {}: {:?}", account, resp.message());
+    }
+    let rendered = output::render(&resp, format, filter, device);
+    if !rendered.is_empty() {
+        println!("{}", rendered);
+    }
+    Ok(())
+}
+
+/// Print the configured account, and how the server sees it.
+async fn whoami(config: &Config) -> Result<(), anyhow::Error> {
+    println!("account: {}", config.account);
+    println!("api_url: {}", config.api_url);
+    println!("local_ip: {}", info::my_ip()?);
+    /* How each server is doing. */
+    for url in config.api_url.urls() {
+        match Api::discover(&url).await {
+            Ok(api) => println!("server {}: {}, api {}", url, api.server(), describe_version(&api)),
+            Err(e) => println!("server {}: {}", url, e),
+        }
+    }
+    let mut servers = Servers::new(&config.api_url, config.server_mode);
+    match servers.get_client_info(&config.account, &config.passwd).await {
+        Ok(resp) if resp.is_ok() => {
+            for line in output::device_lines(&resp) {
+                println!("{}", line);
+            }
         },
-    };
-    /* Read the config from the file. */
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let config = toml::from_str(&contents)?;
+        Ok(resp) => println!("server: {:?}", resp.message()),
+        Err(e) => match e.code() {
+            Some(code) => println!("server: {:?}", code),
+            None => return Err(e.into()),
+        },
+    }
+    Ok(())
+}
+
+/// Load the config from the layers: the config files, the `HERE_*` environment variables,
+/// then the cmdline flags. Ask for the missing fields only if the cmdline is a terminal.
+fn load_config(cli: &Cli) -> Result<Config, anyhow::Error> {
+    /* Looking up the others needs no account. */
+    let account_required = !matches!(cli.command, Some(Command::Query { .. }));
+    let config = config_loader(cli, account_required).load()?;
     Ok(config)
-}
\ No newline at end of file
+}
+
+/// Create the local config file from the cmdline flags and input.
+fn init(cli: &Cli, force: bool) -> Result<(), anyhow::Error> {
+    if Path::new(&cli.config).exists() && !force {
+        anyhow::bail!("The config file {} exists. Use `--force` to overwrite it.", cli.config);
+    }
+    config_loader(cli, true).init()?;
+    println!("Config written to {}.", cli.config);
+    Ok(())
+}
+
+/// Validate the config without asking for anything, and report the result.
+fn check_config(cli: &Cli) -> Result<(), anyhow::Error> {
+    let loader = config_loader(cli, true);
+    for path in loader.existing_files() {
+        println!("Read {}", path.display());
+    }
+    loader.check::<Config>()?;
+    println!("The config is valid.");
+    Ok(())
+}
+
+/// The config layers of the client.
+fn config_loader(cli: &Cli, account_required: bool) -> ConfigLoader {
+    ConfigLoader::new(CONFIG_NAME, &cli.config)
+        .field("account", "Please input the account: ", account_required)
+        .field("passwd", "Please input the password (leave it empty for no password): ", false)
+        .field("api_url", "Please input the API URL (example: http://localhost/here): ", true)
+        .set_override("account", cli.account.clone())
+        .set_override("passwd", cli.passwd.clone())
+        .set_override("api_url", cli.api_url.clone())
+}
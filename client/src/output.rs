@@ -0,0 +1,179 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use clap::ValueEnum;
+use serde_derive::Serialize;
+use utils::server::{GetClientInfoResponse, PresenceState};
+
+/// The version of the JSON output schema. Bump it when a field changes its meaning or goes away.
+const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// The output formats of a query.
+#[derive(ValueEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum Format {
+    /// One address per line.
+    Plain,
+    /// A JSON object of a stable schema.
+    Json,
+    /// `HERE_*=...` lines for `eval`.
+    Env,
+    /// `/etc/hosts` lines.
+    Hosts,
+    /// An OpenSSH `Host` block.
+    SshConfig,
+}
+
+/// Which addresses to output.
+#[derive(Default, Clone, Copy, Debug)]
+pub(crate) struct AddressFilter {
+    pub(crate) ipv4_only: bool,
+    pub(crate) ipv6_only: bool,
+    pub(crate) observed_only: bool,
+}
+
+impl AddressFilter {
+    fn accepts(&self, ip: &IpAddr) -> bool {
+        !(self.ipv4_only && ip.is_ipv6() || self.ipv6_only && ip.is_ipv4())
+    }
+}
+
+/// The JSON output. Its fields only grow, so scripts keep working.
+#[derive(Serialize, Debug)]
+struct QueryOutput<'a> {
+    schema: u32,
+    account: &'a str,
+    state: Option<PresenceState>,
+    last_seen: Option<i64>,
+    expiry: Option<i64>,
+    device: Option<String>,
+    observed: Option<IpAddr>,
+    ipv4s: &'a [Ipv4Addr],
+    ipv6s: &'a [Ipv6Addr],
+}
+
+impl<'a> From<&'a GetClientInfoResponse> for QueryOutput<'a> {
+    fn from(resp: &'a GetClientInfoResponse) -> Self {
+        let presence = resp.presence();
+        Self {
+            schema: JSON_SCHEMA_VERSION,
+            account: resp.account(),
+            state: presence.map(|p| p.state),
+            last_seen: presence.map(|p| p.last_seen),
+            expiry: presence.map(|p| p.expiry),
+            /* As a string, since many JSON parsers cannot hold an `u128`. */
+            device: resp.data().map(|d| d.id.to_string()),
+            observed: resp.observed(),
+            ipv4s: resp.data().map(|d| d.ipv4s.as_slice()).unwrap_or_default(),
+            ipv6s: resp.data().map(|d| d.ipv6s.as_slice()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Render a query response in `format`. `device` adds the device details to the plain format.
+pub(crate) fn render(resp: &GetClientInfoResponse, format: Format, filter: AddressFilter, device: bool) -> String {
+    let mut lines: Vec<String> = vec![];
+    match format {
+        Format::Plain => {
+            if device {
+                lines.extend(device_lines(resp));
+            }
+            lines.extend(addresses(resp, filter).iter().map(IpAddr::to_string));
+        },
+        Format::Json => {
+            lines.push(serde_json::to_string_pretty(&QueryOutput::from(resp)).expect("Cannot serialize the output."));
+        },
+        Format::Env => {
+            let all = AddressFilter::default();
+            let first_of = |filter: AddressFilter| addresses(resp, filter).first().map(IpAddr::to_string).unwrap_or_default();
+            let joined = |filter: AddressFilter| addresses(resp, filter).iter().map(IpAddr::to_string).collect::<Vec<_>>().join(" ");
+            let ipv4s = AddressFilter { ipv4_only: true, ..all };
+            let ipv6s = AddressFilter { ipv6_only: true, ..all };
+            let env = [
+                ("HERE_ACCOUNT", resp.account().to_owned()),
+                ("HERE_STATE", resp.presence().map(|p| p.state.to_string()).unwrap_or_default()),
+                ("HERE_OBSERVED", resp.observed().map(|ip| ip.to_string()).unwrap_or_default()),
+                ("HERE_IPV4", first_of(ipv4s)),
+                ("HERE_IPV6", first_of(ipv6s)),
+                ("HERE_IPV4S", joined(ipv4s)),
+                ("HERE_IPV6S", joined(ipv6s)),
+            ];
+            lines.extend(env.iter().map(|(k, v)| format!("{}={}", k, shell_quote(v))));
+        },
+        Format::Hosts => {
+            lines.extend(addresses(resp, filter).iter().map(|ip| format!("{}\t{}", ip, resp.account())));
+        },
+        Format::SshConfig => {
+            if let Some(ip) = addresses(resp, filter).first() {
+                lines.push(format!("Host {}", resp.account()));
+                lines.push(format!("    HostName {}", ip));
+            }
+        },
+    }
+    lines.join("\n")
+}
+
+/// The device details of a client, in lines.
+pub(crate) fn device_lines(resp: &GetClientInfoResponse) -> Vec<String> {
+    let mut lines = vec![];
+    if let Some(data) = resp.data() {
+        lines.push(format!("device: {}", data.id));
+    }
+    if let Some(presence) = resp.presence() {
+        lines.push(format!("state: {}", presence.state));
+        lines.push(format!("last_seen: {}", presence.last_seen));
+        lines.push(format!("expiry: {}", presence.expiry));
+    }
+    if let Some(observed) = resp.observed() {
+        lines.push(format!("observed: {}", observed));
+    }
+    lines
+}
+
+/// The addresses of a client passing the filter. The observed address comes first.
+fn addresses(resp: &GetClientInfoResponse, filter: AddressFilter) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = resp.observed().into_iter().collect();
+    if let (Some(data), false) = (resp.data(), filter.observed_only) {
+        let reported = data.ipv4s.iter().map(|ip| IpAddr::V4(*ip))
+            .chain(data.ipv6s.iter().map(|ip| IpAddr::V6(*ip)));
+        for address in reported {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+    addresses.retain(|ip| filter.accepts(ip));
+    addresses
+}
+
+/// Quote a value for POSIX shells.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+fn test_response() -> GetClientInfoResponse {
+    use utils::client::ClientInfo;
+    let ips = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    GetClientInfoResponse::new(Some(7), "umoho", None)
+        .set_ok(true)
+        .set_observed(Some("198.51.100.1".parse().unwrap()))
+        .set_data(ClientInfo::new(7, "umoho").set_ips(&ips))
+}
+
+#[test]
+fn test_render_plain_with_filters() {
+    let resp = test_response();
+    assert_eq!(render(&resp, Format::Plain, AddressFilter::default(), false), "198.51.100.1\n192.0.2.1\n2001:db8::1");
+    assert_eq!(render(&resp, Format::Plain, AddressFilter { ipv6_only: true, ..Default::default() }, false), "2001:db8::1");
+    assert_eq!(render(&resp, Format::Plain, AddressFilter { observed_only: true, ..Default::default() }, false), "198.51.100.1");
+}
+
+#[test]
+fn test_render_env_and_ssh_config() {
+    let resp = test_response();
+    let env = render(&resp, Format::Env, AddressFilter::default(), false);
+    assert!(env.contains("HERE_IPV4='198.51.100.1'"));
+    assert!(env.contains("HERE_IPV6S='2001:db8::1'"));
+    let ssh = render(&resp, Format::SshConfig, AddressFilter { ipv6_only: true, ..Default::default() }, false);
+    assert_eq!(ssh, "Host umoho\n    HostName 2001:db8::1");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+}
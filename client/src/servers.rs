@@ -0,0 +1,352 @@
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use utils::client::ClientInfo;
+use utils::config::{self, FieldError};
+use utils::server::{GetClientInfoResponse, PostClientInfoResponse, ResponseMessage};
+
+use crate::api::{Api, ApiError};
+
+/// The `api_url` of the client config: one server, or several of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub(crate) enum ApiUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ApiUrls {
+    pub(crate) fn urls(&self) -> Vec<String> {
+        match self {
+            ApiUrls::One(url) => vec![url.clone()],
+            ApiUrls::Many(urls) => urls.clone(),
+        }
+    }
+
+    pub(crate) fn validate(&self, errors: &mut Vec<FieldError>) {
+        match self {
+            ApiUrls::One(url) => errors.extend(config::check_http_url("api_url", url)),
+            ApiUrls::Many(urls) if urls.is_empty() => errors.push(
+                FieldError::new("api_url", "no server is listed").suggest("list at least one API URL")
+            ),
+            ApiUrls::Many(urls) => {
+                for (i, url) in urls.iter().enumerate() {
+                    errors.extend(config::check_http_url(&format!("api_url[{}]", i), url));
+                }
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ApiUrls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.urls().join(", "))
+    }
+}
+
+/// How the posts go to several servers.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ServerMode {
+    /// Post to the server in use. If it fails, try the next ones in order, and keep using
+    /// the one which takes the post.
+    #[default]
+    Failover,
+    /// Post to every server.
+    FanOut,
+}
+
+/// How a server has been doing.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Health {
+    /// The consecutive failures.
+    pub(crate) failures: u32,
+    /// Of the latest failure, cleared by a success.
+    pub(crate) last_error: Option<String>,
+}
+
+#[derive(Clone)]
+struct Server {
+    url: String,
+    /// Discovered on the first use.
+    api: Option<Api>,
+    health: Health,
+}
+
+impl Server {
+    /// The API of the server, discovered if it is not yet.
+    async fn api(&mut self) -> Result<&Api, ApiError> {
+        if self.api.is_none() {
+            self.api = Some(Api::discover(&self.url).await?);
+        }
+        Ok(self.api.as_ref().expect("The API is discovered."))
+    }
+
+    fn succeed(&mut self) {
+        if self.health.failures > 0 {
+            info!(server = %self.url, failures = self.health.failures, "The server is back.");
+        }
+        self.health = Health::default();
+    }
+
+    fn fail(&mut self, e: &dyn std::fmt::Display) {
+        if self.health.failures == 0 {
+            warn!(server = %self.url, error = %e, "The server failed.");
+        }
+        else {
+            debug!(server = %self.url, error = %e, failures = self.health.failures + 1, "The server failed again.");
+        }
+        self.health.failures += 1;
+        self.health.last_error = Some(e.to_string());
+    }
+}
+
+/// A post taken by one or more servers.
+pub(crate) struct Posted {
+    /// The response of the first server which took it.
+    pub(crate) resp: PostClientInfoResponse,
+    /// The servers which took it.
+    pub(crate) accepted: Vec<String>,
+}
+
+/// The servers of the config, with their health.
+pub(crate) struct Servers {
+    mode: ServerMode,
+    servers: Vec<Server>,
+    /// The server in use, in the failover mode.
+    current: usize,
+}
+
+impl Servers {
+    pub(crate) fn new(urls: &ApiUrls, mode: ServerMode) -> Self {
+        let servers = urls.urls().into_iter().map(|url| Server { url, api: None, health: Health::default() }).collect();
+        Self { mode, servers, current: 0 }
+    }
+
+    /// The server in use and the ones after it, wrapping around.
+    fn in_order(&self) -> Vec<usize> {
+        (0..self.servers.len()).map(|i| (self.current + i) % self.servers.len()).collect()
+    }
+
+    /// The API of the server in use, or of the first one after it which can be reached.
+    /// The one found is used from now on.
+    pub(crate) async fn discover(&mut self) -> Result<Api, ApiError> {
+        let mut errors = vec![];
+        for i in self.in_order() {
+            let server = &mut self.servers[i];
+            match server.api().await {
+                Ok(api) => {
+                    let api = api.clone();
+                    server.succeed();
+                    self.current = i;
+                    return Ok(api);
+                },
+                Err(e) => {
+                    server.fail(&e);
+                    errors.push(e);
+                },
+            }
+        }
+        Err(worst(errors))
+    }
+
+    /// Post my information by the mode. Fails only if no server takes it.
+    pub(crate) async fn post_my_info(&mut self, info: &ClientInfo) -> Result<Posted, ApiError> {
+        match self.mode {
+            ServerMode::Failover => self.post_failover(info).await,
+            ServerMode::FanOut => self.post_fan_out(info).await,
+        }
+    }
+
+    async fn post_failover(&mut self, info: &ClientInfo) -> Result<Posted, ApiError> {
+        let mut errors = vec![];
+        for i in self.in_order() {
+            let server = &mut self.servers[i];
+            let result = match server.api().await {
+                Ok(api) => api.post_my_info(info).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(resp) => {
+                    server.succeed();
+                    if i != self.current {
+                        info!(server = %server.url, "Failed over to the server.");
+                        self.current = i;
+                    }
+                    return Ok(Posted { resp, accepted: vec![server.url.clone()] });
+                },
+                Err(e) => {
+                    server.fail(&e);
+                    errors.push(e);
+                },
+            }
+        }
+        Err(worst(errors))
+    }
+
+    /// Post to every server at once, so a slow one does not hold up the others.
+    async fn post_fan_out(&mut self, info: &ClientInfo) -> Result<Posted, ApiError> {
+        let tasks: Vec<_> = self.servers.iter().cloned().map(|mut server| {
+            let info = info.clone();
+            tokio::spawn(async move {
+                let result = match server.api().await {
+                    Ok(api) => api.post_my_info(&info).await,
+                    Err(e) => Err(e),
+                };
+                (server.api, result)
+            })
+        }).collect();
+        let (mut taken, mut accepted, mut errors) = (None, vec![], vec![]);
+        for (server, task) in self.servers.iter_mut().zip(tasks) {
+            let (api, result) = task.await.expect("Posting to a server panicked.");
+            server.api = api;
+            match result {
+                Ok(resp) => {
+                    server.succeed();
+                    taken.get_or_insert(resp);
+                    accepted.push(server.url.clone());
+                },
+                Err(e) => {
+                    server.fail(&e);
+                    errors.push(e);
+                },
+            }
+        }
+        match taken {
+            Some(resp) => Ok(Posted { resp, accepted }),
+            None => Err(worst(errors)),
+        }
+    }
+
+    /// Look up an account on the server in use, failing over to the others.
+    pub(crate) async fn get_client_info(&mut self, account: &str, passwd: &Option<String>) -> Result<GetClientInfoResponse, ApiError> {
+        let mut lookup = self.lookup();
+        let result = lookup.get_client_info(account, passwd).await;
+        self.finish(lookup);
+        result
+    }
+
+    /// A lookup on the servers in order, which does not hold them. So the servers shared
+    /// behind a lock are not locked during the requests. Hand it back to `finish` after.
+    pub(crate) fn lookup(&self) -> Lookup {
+        let candidates = self.in_order().into_iter()
+            .map(|i| (i, self.servers[i].url.clone(), self.servers[i].api.clone()))
+            .collect();
+        Lookup { candidates, tried: vec![], answered: None }
+    }
+
+    /// Count what a lookup did on the servers, and use the one which answered from now on.
+    pub(crate) fn finish(&mut self, lookup: Lookup) {
+        for (i, api, outcome) in lookup.tried {
+            let server = &mut self.servers[i];
+            if api.is_some() {
+                server.api = api;
+            }
+            match outcome {
+                Ok(_) => server.succeed(),
+                Err(e) => server.fail(&e),
+            }
+        }
+        if let Some(i) = lookup.answered {
+            self.current = i;
+        }
+    }
+
+    /// Take my information offline on every server which may have it.
+    pub(crate) async fn deregister_my_info(&mut self, info: &ClientInfo) -> Vec<(String, Result<PostClientInfoResponse, ApiError>)> {
+        let mut results = vec![];
+        for server in &mut self.servers {
+            /* Only the servers reached before. */
+            if let Some(api) = &server.api {
+                results.push((server.url.clone(), api.deregister_my_info(info).await));
+            }
+        }
+        results
+    }
+
+    /// Every server, and how it has been doing.
+    pub(crate) fn health(&self) -> impl Iterator<Item = (&str, &Health)> {
+        self.servers.iter().map(|s| (s.url.as_str(), &s.health))
+    }
+}
+
+/// A lookup of an account on the servers, from `Servers::lookup`.
+pub(crate) struct Lookup {
+    /// The index, the URL and the API if discovered, of each server, in order.
+    candidates: Vec<(usize, String, Option<Api>)>,
+    /// The index of each server tried, its API, and why it failed if it did.
+    tried: Vec<(usize, Option<Api>, Result<(), String>)>,
+    /// The server which answered.
+    answered: Option<usize>,
+}
+
+impl Lookup {
+    /// Look up an account on the servers in order, until one answers.
+    pub(crate) async fn get_client_info(&mut self, account: &str, passwd: &Option<String>) -> Result<GetClientInfoResponse, ApiError> {
+        let mut errors = vec![];
+        for (i, url, api) in std::mem::take(&mut self.candidates) {
+            let api = match api {
+                Some(api) => api,
+                None => match Api::discover(&url).await {
+                    Ok(api) => api,
+                    Err(e) => {
+                        self.tried.push((i, None, Err(e.to_string())));
+                        errors.push(e);
+                        continue;
+                    },
+                },
+            };
+            let result = api.get_client_info(account, passwd).await;
+            let api = Some(api);
+            match result {
+                Ok(resp) => {
+                    self.tried.push((i, api, Ok(())));
+                    self.answered = Some(i);
+                    return Ok(resp);
+                },
+                /* A wrong password, a lockout by wrong passwords, or an invalid request is the same
+                on every server, and it is not the fault of this one. */
+                Err(e) if matches!(e.code(), Some(ResponseMessage::InvalidPassword | ResponseMessage::LockedOut | ResponseMessage::InvalidRequest)) => {
+                    self.tried.push((i, api, Ok(())));
+                    return Err(e);
+                },
+                /* Missing there, but another server may have taken the posts, as in the failover mode. */
+                Err(e) if e.code() == Some(ResponseMessage::NotFound) => {
+                    self.tried.push((i, api, Ok(())));
+                    errors.push(e);
+                },
+                Err(e) => {
+                    self.tried.push((i, api, Err(e.to_string())));
+                    errors.push(e);
+                },
+            }
+        }
+        Err(worst(errors))
+    }
+}
+
+/// The error to act on when no server took a request: a retryable one if any,
+/// so the client waits for the servers to come back.
+fn worst(mut errors: Vec<ApiError>) -> ApiError {
+    let i = errors.iter().position(ApiError::is_retryable).unwrap_or(0);
+    errors.swap_remove(i)
+}
+
+#[test]
+fn test_api_urls() {
+    #[derive(Deserialize)]
+    struct Config {
+        api_url: ApiUrls,
+        #[serde(default)]
+        server_mode: ServerMode,
+    }
+    let config: Config = toml::from_str(r#"api_url = "http://a/here""#).unwrap();
+    assert_eq!(config.api_url.urls(), ["http://a/here"]);
+    assert_eq!(config.server_mode, ServerMode::Failover);
+    let config: Config = toml::from_str("api_url = [\"http://a/here\", \"http://b/here\"]\nserver_mode = \"fan_out\"").unwrap();
+    assert_eq!(config.api_url.urls(), ["http://a/here", "http://b/here"]);
+    assert_eq!(config.server_mode, ServerMode::FanOut);
+
+    let servers = Servers { current: 1, ..Servers::new(&config.api_url, ServerMode::Failover) };
+    assert_eq!(servers.in_order(), [1, 0]);
+}
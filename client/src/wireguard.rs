@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use utils::config::{self, FieldError};
+use utils::logging::Redacted;
+use utils::server::{GetClientInfoResponse, PresenceState, ResponseMessage};
+
+use crate::servers::Servers;
+
+/// Default seconds between two rounds of peer lookups.
+const DEFAULT_UPDATE_INTERVAL: u64 = 60;
+
+/// The bounds of the seconds between two rounds of peer lookups.
+const UPDATE_INTERVAL_RANGE: std::ops::RangeInclusive<u64> = 1..=86400;
+
+/// The length of a WireGuard key in base64.
+const KEY_BASE64_LENGTH: usize = 44;
+
+/// The `[wireguard]` section of the client config.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WireGuardConfig {
+    /// The WireGuard interface, used by the `wg set` commands. Example: `wg0`.
+    pub(crate) interface: String,
+    /// The `wg-quick` config file to rewrite. `wg set` commands are emitted if it is `None`.
+    pub(crate) config_path: Option<String>,
+    /// Run the `wg set` commands instead of printing them.
+    #[serde(default)]
+    pub(crate) apply: bool,
+    /// Seconds between two rounds of peer lookups.
+    #[serde(default = "default_interval")]
+    pub(crate) interval: u64,
+    /// Which Here account each peer is.
+    #[serde(default)]
+    pub(crate) peers: Vec<PeerMapping>,
+}
+
+/// Maps a WireGuard peer public key to a Here account.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PeerMapping {
+    pub(crate) public_key: String,
+    pub(crate) account: String,
+    pub(crate) passwd: Option<String>,
+    /// The listen port of the peer.
+    pub(crate) port: u16,
+    /// Use an IPv6 address when the peer has both.
+    #[serde(default)]
+    pub(crate) prefer_ipv6: bool,
+}
+
+impl std::fmt::Debug for PeerMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerMapping")
+            .field("public_key", &self.public_key)
+            .field("account", &self.account)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("port", &self.port)
+            .field("prefer_ipv6", &self.prefer_ipv6)
+            .finish()
+    }
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_UPDATE_INTERVAL
+}
+
+impl WireGuardConfig {
+    /// Push an error for every invalid field. The keys are under `wireguard`.
+    pub(crate) fn validate(&self, errors: &mut Vec<FieldError>) {
+        if self.interface.is_empty() {
+            errors.push(FieldError::new("wireguard.interface", "the interface is empty").suggest("such as wg0"));
+        }
+        if let Some(path) = &self.config_path {
+            errors.extend(config::check_path_exists("wireguard.config_path", path));
+        }
+        errors.extend(config::check_range("wireguard.interval", self.interval, UPDATE_INTERVAL_RANGE));
+        for (i, peer) in self.peers.iter().enumerate() {
+            let key = |field: &str| format!("wireguard.peers[{}].{}", i, field);
+            let is_base64 = peer.public_key.chars().all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c));
+            if peer.public_key.len() != KEY_BASE64_LENGTH || !peer.public_key.ends_with('=') || !is_base64 {
+                errors.push(
+                    FieldError::new(key("public_key"), format!("`{}` is not a WireGuard public key", peer.public_key))
+                        .suggest("copy it from `wg show` or `wg pubkey`")
+                );
+            }
+            errors.extend(config::check_account(&key("account"), &peer.account));
+            if peer.port == 0 {
+                errors.push(FieldError::new(key("port"), "the port is 0").suggest("use the listen port of the peer"));
+            }
+        }
+    }
+}
+
+/// Look up every mapped peer forever, and update the endpoints which changed.
+/// The servers are shared with the posts.
+pub(crate) async fn run_updater(servers: Arc<Mutex<Servers>>, config: WireGuardConfig) {
+    /* Endpoints we have already applied, keyed by public key. */
+    let mut applied: HashMap<String, SocketAddr> = HashMap::new();
+    loop {
+        let endpoints = lookup_endpoints(&servers, &config.peers).await;
+        /* Only keep the endpoints which changed since last round. */
+        let changed: HashMap<String, SocketAddr> = endpoints.into_iter()
+            .filter(|(key, endpoint)| applied.get(key) != Some(endpoint))
+            .collect();
+        if !changed.is_empty() {
+            match apply_endpoints(&config, &changed) {
+                Ok(_) => applied.extend(changed),
+                Err(e) => error!("Cannot update the WireGuard endpoints: {}", e),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+/// Query the server for every peer, and return the endpoints of the peers found.
+async fn lookup_endpoints(servers: &Mutex<Servers>, peers: &[PeerMapping]) -> HashMap<String, SocketAddr> {
+    let mut endpoints = HashMap::new();
+    for peer in peers {
+        /* Locked only to take the servers and to count the lookup, so a post is not held up by the requests. */
+        let mut lookup = servers.lock().await.lookup();
+        let found = lookup.get_client_info(&peer.account, &peer.passwd).await;
+        servers.lock().await.finish(lookup);
+        match found {
+            Ok(resp) => {
+                debug!(?resp, "Server response.");
+                match pick_address(&resp, peer.prefer_ipv6) {
+                    Some(ip) => {
+                        endpoints.insert(peer.public_key.clone(), SocketAddr::new(ip, peer.port));
+                    },
+                    None => warn!("No address of the account {} yet.", peer.account),
+                }
+            },
+            Err(e) if e.code() == Some(ResponseMessage::NotFound) => warn!("No address of the account {} yet.", peer.account),
+            Err(e) => warn!("Cannot look up the account {}: {}", peer.account, e),
+        }
+    }
+    endpoints
+}
+
+/// Pick the address to reach a client. The address the server observed comes first,
+/// then the addresses the client reported. An offline client has no address.
+fn pick_address(resp: &GetClientInfoResponse, prefer_ipv6: bool) -> Option<IpAddr> {
+    if !resp.is_ok() || resp.presence().map(|p| p.state) == Some(PresenceState::Offline) {
+        return None;
+    }
+    if let Some(observed) = resp.observed() {
+        return Some(observed);
+    }
+    let data = resp.data()?;
+    let ipv4 = data.ipv4s.first().map(|ip| IpAddr::V4(*ip));
+    let ipv6 = data.ipv6s.first().map(|ip| IpAddr::V6(*ip));
+    if prefer_ipv6 { ipv6.or(ipv4) } else { ipv4.or(ipv6) }
+}
+
+/// Rewrite the config file, or emit the `wg set` commands.
+fn apply_endpoints(config: &WireGuardConfig, endpoints: &HashMap<String, SocketAddr>) -> Result<(), anyhow::Error> {
+    match &config.config_path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)?;
+            let (new_contents, changed) = rewrite_endpoints(&contents, endpoints);
+            if changed {
+                /* Write to a temporary file first, so the config is never half written. */
+                let tmp_path = format!("{}.tmp", path);
+                std::fs::write(&tmp_path, new_contents)?;
+                std::fs::rename(&tmp_path, path)?;
+                info!("Updated the WireGuard endpoints in {}.", path);
+            }
+        },
+        None => {
+            for (public_key, endpoint) in endpoints {
+                let args = ["set", &config.interface, "peer", public_key, "endpoint", &endpoint.to_string()];
+                if config.apply {
+                    let status = std::process::Command::new("wg").args(args).status()?;
+                    if !status.success() {
+                        anyhow::bail!("`wg {}` exited with {}", args.join(" "), status);
+                    }
+                }
+                else {
+                    println!("wg {}", args.join(" "));
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Replace the `Endpoint =` line of every `[Peer]` section whose `PublicKey` is in `endpoints`,
+/// or add one if the section has none. Return the new contents, and whether anything changed.
+fn rewrite_endpoints(contents: &str, endpoints: &HashMap<String, SocketAddr>) -> (String, bool) {
+    let mut output: Vec<String> = vec![];
+    let mut section: Vec<String> = vec![];
+    let mut changed = false;
+    for line in contents.lines() {
+        /* A new section begins, so the previous one is finished. */
+        if line.trim_start().starts_with('[') {
+            changed |= flush_section(&mut section, &mut output, endpoints);
+        }
+        section.push(line.to_owned());
+    }
+    changed |= flush_section(&mut section, &mut output, endpoints);
+
+    let mut new_contents = output.join("\n");
+    if contents.ends_with('\n') {
+        new_contents.push('\n');
+    }
+    (new_contents, changed)
+}
+
+/// Move the lines of a section into `output`, with the endpoint rewritten if it is a known peer.
+fn flush_section(section: &mut Vec<String>, output: &mut Vec<String>, endpoints: &HashMap<String, SocketAddr>) -> bool {
+    let mut changed = false;
+    let is_peer = section.first().map(|l| l.trim().eq_ignore_ascii_case("[Peer]")).unwrap_or(false);
+    let endpoint = section.iter()
+        .filter_map(|l| key_value(l))
+        .find(|(k, _)| k.eq_ignore_ascii_case("PublicKey"))
+        .and_then(|(_, v)| endpoints.get(v));
+    if let (true, Some(endpoint)) = (is_peer, endpoint) {
+        let new_line = format!("Endpoint = {}", endpoint);
+        match section.iter().position(|l| key_value(l).map(|(k, _)| k.eq_ignore_ascii_case("Endpoint")).unwrap_or(false)) {
+            Some(i) => {
+                if section[i].trim() != new_line {
+                    section[i] = new_line;
+                    changed = true;
+                }
+            },
+            None => {
+                /* Put it after the last non-blank line of the section. */
+                let at = section.iter().rposition(|l| !l.trim().is_empty()).map(|i| i + 1).unwrap_or(0);
+                section.insert(at, new_line);
+                changed = true;
+            },
+        }
+    }
+    output.append(section);
+    changed
+}
+
+/// Split a `Key = Value` line. Return `None` for comments and section headers.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with('[') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
+
+#[test]
+fn test_rewrite_endpoints() {
+    let contents = "[Interface]\nPrivateKey = aaa=\n\n[Peer]\nPublicKey = bbb=\nEndpoint = 10.0.0.1:51820\n\n[Peer]\nPublicKey = ccc=\nAllowedIPs = 10.1.0.0/24\n";
+    let mut endpoints = HashMap::new();
+    endpoints.insert("bbb=".to_owned(), "192.0.2.1:51820".parse().unwrap());
+    endpoints.insert("ccc=".to_owned(), "[2001:db8::1]:51821".parse().unwrap());
+    let (new_contents, changed) = rewrite_endpoints(contents, &endpoints);
+    assert!(changed);
+    assert_eq!(new_contents, "[Interface]\nPrivateKey = aaa=\n\n[Peer]\nPublicKey = bbb=\nEndpoint = 192.0.2.1:51820\n\n[Peer]\nPublicKey = ccc=\nAllowedIPs = 10.1.0.0/24\nEndpoint = [2001:db8::1]:51821\n");
+    /* Nothing changes the second time. */
+    assert_eq!(rewrite_endpoints(&new_contents, &endpoints), (new_contents.clone(), false));
+}
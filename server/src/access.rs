@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::{body::Body, extract::MatchedPath, http::{Request, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use ipnet::IpNet;
+use serde_derive::{Serialize, Deserialize};
+use tracing::info;
+use utils::config::{self, FieldError};
+use utils::server::{Endpoint, ResponseMessage};
+
+use crate::metrics::METRICS;
+use crate::problem::ApiError;
+use crate::proxy::ClientAddr;
+use crate::ratelimit::accounts_of;
+use crate::reload::LiveConfig;
+use crate::restful;
+
+/// Which networks may call an endpoint, in the server config. With `account`, only
+/// the requests for the account are checked. A denied network wins over an allowed one,
+/// and an empty `allow` allows all the others.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AccessRule {
+    /// The name of the endpoint, as in the discovery document.
+    pub(crate) endpoint: Endpoint,
+    pub(crate) account: Option<String>,
+    /// CIDRs, or single addresses.
+    #[serde(default)]
+    pub(crate) allow: Vec<String>,
+    #[serde(default)]
+    pub(crate) deny: Vec<String>,
+}
+
+impl AccessRule {
+    /// Whether the rule lets `ip` call its endpoint.
+    fn admits(&self, ip: IpAddr) -> bool {
+        let contains = |cidrs: &[String]| cidrs.iter().filter_map(|cidr| parse_cidr(cidr)).any(|net| net.contains(&ip));
+        !contains(&self.deny) && (self.allow.is_empty() || contains(&self.allow))
+    }
+}
+
+/// A CIDR, or a single address as the network of it alone.
+pub(crate) fn parse_cidr(value: &str) -> Option<IpNet> {
+    value.parse::<IpNet>().ok().or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Check the access rules of the config: known endpoints, valid accounts and CIDRs.
+pub(crate) fn validate_access(rules: &[AccessRule], errors: &mut Vec<FieldError>) {
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.endpoint == Endpoint::Unknown {
+            errors.push(
+                FieldError::new(format!("access[{}].endpoint", i), "unknown endpoint")
+                    .suggest("use an endpoint name of the discovery document, such as `post_client_info`")
+            );
+        }
+        if let Some(account) = &rule.account {
+            errors.extend(config::check_account(&format!("access[{}].account", i), account));
+        }
+        for (list, cidrs) in [("allow", &rule.allow), ("deny", &rule.deny)] {
+            for (j, cidr) in cidrs.iter().enumerate() {
+                if parse_cidr(cidr).is_none() {
+                    errors.push(
+                        FieldError::new(format!("access[{}].{}[{}]", i, list, j), format!("`{}` is not a CIDR", cidr))
+                            .suggest("use a network such as 10.0.0.0/8 or fd00::/8, or a single address")
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Refuse with `403` the requests from the networks the access rules of their endpoint
+/// do not allow. The rules are read from the live config, so they can be reloaded.
+pub(crate) async fn check_access(req: Request<Body>, next: Next<Body>) -> Response {
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        None => return next.run(req).await,
+    };
+    let rules: Vec<AccessRule> = match (restful::endpoint_of_route(&route), req.extensions().get::<Arc<LiveConfig>>()) {
+        (Some(endpoint), Some(live)) => live.current().access.iter().filter(|r| r.endpoint == endpoint).cloned().collect(),
+        _ => vec![],
+    };
+    let ip = match req.extensions().get::<ClientAddr>() {
+        Some(ClientAddr(ip)) if !rules.is_empty() => *ip,
+        _ => return next.run(req).await,
+    };
+    let (req, accounts) = if rules.iter().any(|r| r.account.is_some()) {
+        match accounts_of(req).await {
+            Ok(read) => read,
+            Err(e) => return e.into_response(),
+        }
+    }
+    else {
+        (req, vec![])
+    };
+    let refusing = rules.iter().find(|rule| {
+        let applies = match &rule.account {
+            Some(account) => accounts.contains(account),
+            None => true,
+        };
+        applies && !rule.admits(ip)
+    });
+    if let Some(rule) = refusing {
+        METRICS.observe_access_denied(&route);
+        info!(%ip, account = ?rule.account, "Refused a request from a network not allowed.");
+        let detail = match &rule.account {
+            Some(account) => format!("The address {} may not call {} for the account `{}`.", ip, route, account),
+            None => format!("The address {} may not call {}.", ip, route),
+        };
+        return ApiError::new(StatusCode::FORBIDDEN, ResponseMessage::AddressNotAllowed, detail).into_response();
+    }
+    next.run(req).await
+}
+
+#[test]
+fn test_access_rule() {
+    let rule = AccessRule {
+        endpoint: Endpoint::PostClientInfo,
+        account: Some("office-nas".to_owned()),
+        allow: vec!["10.0.0.0/8".to_owned(), "fd00::1".to_owned()],
+        deny: vec!["10.0.99.0/24".to_owned()],
+    };
+    assert!(rule.admits("10.1.2.3".parse().unwrap()));
+    assert!(rule.admits("fd00::1".parse().unwrap()));
+    assert!(!rule.admits("10.0.99.1".parse().unwrap()));
+    assert!(!rule.admits("192.0.2.1".parse().unwrap()));
+    let deny_only = AccessRule { allow: vec![], ..rule.clone() };
+    assert!(deny_only.admits("192.0.2.1".parse().unwrap()));
+
+    let mut errors = vec![];
+    validate_access(&[AccessRule { deny: vec!["10.0.0.0/33".to_owned()], ..rule }], &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].key, "access[0].deny[0]");
+}
@@ -0,0 +1,34 @@
+use clap::{Parser, Subcommand};
+use utils::logging::LogFormat;
+
+/// The server of Here. Keeps the IPs the clients posted.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub(crate) struct Cli {
+    /// The local config file. `/etc/here/server.toml` and `~/.config/here/server.toml` are read before it.
+    #[arg(long, global = true, default_value = crate::DEFAULT_CONFIG_PATH)]
+    pub(crate) config: String,
+
+    /// Override the bind address in the config. Example: 127.0.0.1:8080
+    #[arg(long, global = true)]
+    pub(crate) bind: Option<String>,
+
+    /// The log level, or a filter such as `warn,server=debug`.
+    #[arg(long, global = true, env = "HERE_LOG_LEVEL", default_value = "info")]
+    pub(crate) log_level: String,
+
+    /// The log format: `text` or `json`.
+    #[arg(long, global = true, env = "HERE_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    pub(crate) log_format: LogFormat,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Run the server. The default.
+    Run,
+    /// Load and validate the config, report the problems, then exit.
+    CheckConfig,
+}
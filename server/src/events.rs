@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc, serde::ts_seconds};
+use serde_derive::{Serialize, Deserialize};
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+use utils::client::verify_passwd_hash;
+use utils::logging::Redacted;
+
+use crate::storage::ClientInfoRecord;
+use crate::webhook::WebhookDispatcher;
+
+/// How many recent events are kept for resuming subscribers.
+const HISTORY_CAPACITY: usize = 1024;
+
+/// What happened to the record of an account.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EventKind {
+    /// The addresses of the account changed, or the account came online.
+    Changed,
+    /// The account posted again with the same addresses.
+    Renewed,
+    /// The record of the account ran out of its lifetime, the device is offline.
+    Expired,
+}
+
+impl EventKind {
+    /// The name in the serialized events.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EventKind::Changed => "changed",
+            EventKind::Renewed => "renewed",
+            EventKind::Expired => "expired",
+        }
+    }
+}
+
+/// The id of an event, used as the resume cursor: the epoch of the `EventHub`, which is
+/// new in every server process, and the sequence number in it. Written as `<epoch>:<seq>`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(into = "String", try_from = "String")]
+pub(crate) struct EventId {
+    pub(crate) epoch: u64,
+    pub(crate) seq: u64,
+}
+
+impl Display for EventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (epoch, seq) = s.split_once(':').ok_or_else(|| format!("`{}` is not `<epoch>:<seq>`", s))?;
+        match (epoch.parse(), seq.parse()) {
+            (Ok(epoch), Ok(seq)) => Ok(Self { epoch, seq }),
+            _ => Err(format!("`{}` is not `<epoch>:<seq>`", s)),
+        }
+    }
+}
+
+impl From<EventId> for String {
+    fn from(id: EventId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for EventId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// An event about the record of an account.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub(crate) struct ClientEvent {
+    /// The id given by the `EventHub`. Used as the resume cursor.
+    #[serde(default)]
+    pub(crate) id: EventId,
+    pub(crate) event: EventKind,
+    pub(crate) account: String,
+    pub(crate) ipv4s: Vec<Ipv4Addr>,
+    pub(crate) ipv6s: Vec<Ipv6Addr>,
+    pub(crate) observed: Option<IpAddr>,
+    #[serde(with = "ts_seconds")]
+    pub(crate) time: DateTime<Utc>,
+    /// The password hash of the account. Never serialized, only for checking who may see the event.
+    #[serde(skip)]
+    pub(crate) passwd: Option<String>,
+}
+
+impl std::fmt::Debug for ClientEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientEvent")
+            .field("id", &self.id)
+            .field("event", &self.event)
+            .field("account", &self.account)
+            .field("ipv4s", &self.ipv4s)
+            .field("ipv6s", &self.ipv6s)
+            .field("observed", &self.observed)
+            .field("time", &self.time)
+            .field("passwd", &Redacted(&self.passwd))
+            .finish()
+    }
+}
+
+impl ClientEvent {
+    /// Create an event of `kind` from a record, and the event time is an UTC now.
+    pub(crate) fn new(kind: EventKind, record: &ClientInfoRecord) -> Self {
+        Self {
+            id: EventId::default(),
+            event: kind,
+            account: record.client_info.account.clone(),
+            ipv4s: record.client_info.ipv4s.clone(),
+            ipv6s: record.client_info.ipv6s.clone(),
+            observed: record.observed,
+            time: Utc::now(),
+            passwd: record.client_info.passwd.clone(),
+        }
+    }
+
+    /// Whether a subscriber knowing `passwd_plaintext` may see this event. The events of
+    /// an account without a password are seen by nobody, as its addresses are not told.
+    pub(crate) fn is_visible_with(&self, passwd_plaintext: &str) -> bool {
+        match &self.passwd {
+            Some(hash) => verify_passwd_hash(hash, passwd_plaintext),
+            None => false,
+        }
+    }
+}
+
+/// Where the events go. Shared by the RESTful API server and the cleaning thread.
+pub(crate) struct EventHub {
+    webhooks: Arc<WebhookDispatcher>,
+    /// Random for every hub, so the cursors of another server process are told apart.
+    epoch: u64,
+    /// The latest sequence number and the recent events, locked together
+    /// so that a subscriber never misses an event between them.
+    history: Mutex<(u64, VecDeque<ClientEvent>)>,
+    sender: broadcast::Sender<ClientEvent>,
+    /// Set when shutting down, to end the subscriptions.
+    closed: watch::Sender<bool>,
+}
+
+impl EventHub {
+    pub(crate) fn new(webhooks: Arc<WebhookDispatcher>) -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_CAPACITY);
+        Self {
+            webhooks,
+            epoch: rand::random(),
+            history: Mutex::new((0, VecDeque::with_capacity(HISTORY_CAPACITY))),
+            sender,
+            closed: watch::channel(false).0,
+        }
+    }
+
+    /// End every subscription, for shutting down. The events are still published to the webhooks.
+    pub(crate) fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// Give the event an id, and hand it to every consumer.
+    pub(crate) fn publish(&self, mut event: ClientEvent) {
+        {
+            let mut history = self.history.lock().expect("Event history lock poisoned.");
+            history.0 += 1;
+            event.id = EventId { epoch: self.epoch, seq: history.0 };
+            if history.1.len() == HISTORY_CAPACITY {
+                history.1.pop_front();
+            }
+            history.1.push_back(event.clone());
+            /* It is fine if nobody is subscribing. */
+            let _ = self.sender.send(event.clone());
+        }
+        debug!(?event, "Publish an event.");
+        /* Renewals are too frequent for the webhooks. */
+        if event.event != EventKind::Renewed {
+            self.webhooks.enqueue(&event);
+        }
+    }
+
+    /// Subscribe to the events after the `cursor` event.
+    pub(crate) fn subscribe(&self, cursor: Option<EventId>) -> Subscription {
+        let history = self.history.lock().expect("Event history lock poisoned.");
+        let (backlog, missed) = match cursor {
+            /* The cursor is from another server process, such as before a restart. */
+            Some(cursor) if cursor.epoch != self.epoch => (VecDeque::new(), true),
+            Some(EventId { seq: cursor, .. }) => {
+                let backlog: VecDeque<ClientEvent> = history.1.iter().filter(|e| e.id.seq > cursor).cloned().collect();
+                /* Something between the cursor and the oldest kept event is lost. */
+                let oldest = backlog.front().map(|e| e.id.seq).unwrap_or(history.0 + 1);
+                (backlog, cursor > history.0 || oldest > cursor + 1)
+            },
+            None => (VecDeque::new(), false),
+        };
+        Subscription {
+            backlog,
+            missed,
+            receiver: self.sender.subscribe(),
+            closed: self.closed.subscribe(),
+        }
+    }
+}
+
+/// What a subscriber receives from the `EventHub`.
+pub(crate) enum Received {
+    Event(ClientEvent),
+    /// Some events were dropped before the subscriber could receive them.
+    /// The subscriber should query the accounts again.
+    Missed,
+}
+
+/// A subscription of the events. Yields the kept events after the cursor first, then the new ones.
+pub(crate) struct Subscription {
+    backlog: VecDeque<ClientEvent>,
+    missed: bool,
+    receiver: broadcast::Receiver<ClientEvent>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Subscription {
+    /// Wait for the next event. Return `None` if the hub is closed or gone.
+    pub(crate) async fn recv(&mut self) -> Option<Received> {
+        if *self.closed.borrow() {
+            return None;
+        }
+        if self.missed {
+            self.missed = false;
+            return Some(Received::Missed);
+        }
+        if let Some(event) = self.backlog.pop_front() {
+            return Some(Received::Event(event));
+        }
+        let received = tokio::select! {
+            received = self.receiver.recv() => received,
+            _ = self.closed.wait_for(|closed| *closed) => return None,
+        };
+        match received {
+            Ok(event) => Some(Received::Event(event)),
+            Err(broadcast::error::RecvError::Lagged(_)) => Some(Received::Missed),
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_subscribe_with_cursor() {
+    use utils::client::ClientInfo;
+    let outbox_path = std::env::temp_dir().join(format!("here-test-events-{}.json", std::process::id()));
+    let webhooks = Arc::new(WebhookDispatcher::new(vec![], outbox_path.to_str().unwrap()).unwrap());
+    let hub = EventHub::new(webhooks);
+    let record = ClientInfoRecord::new(ClientInfo::new(1, "umoho"), 60);
+    for kind in [EventKind::Changed, EventKind::Renewed, EventKind::Expired] {
+        hub.publish(ClientEvent::new(kind, &record));
+    }
+
+    /* Resume after the first event: the other two are replayed. */
+    let cursor = EventId { epoch: hub.epoch, seq: 1 };
+    assert_eq!(cursor.to_string().parse(), Ok(cursor));
+    let mut subscription = hub.subscribe(Some(cursor));
+    for seq in [2, 3] {
+        match subscription.recv().await {
+            Some(Received::Event(e)) => assert_eq!(e.id, EventId { epoch: hub.epoch, seq }),
+            _ => panic!("Expected the event {}.", seq),
+        }
+    }
+    /* A cursor from before a server restart cannot be resumed, even if its sequence number is kept. */
+    let earlier = EventId { epoch: hub.epoch.wrapping_add(1), seq: 1 };
+    assert!(matches!(hub.subscribe(Some(earlier)).recv().await, Some(Received::Missed)));
+    assert!(matches!(hub.subscribe(Some(EventId { epoch: hub.epoch, seq: 10 })).recv().await, Some(Received::Missed)));
+    /* Closing ends the waiting subscriptions. */
+    let mut subscription = hub.subscribe(None);
+    let waiting = tokio::spawn(async move { subscription.recv().await.is_none() });
+    hub.close();
+    assert!(waiting.await.unwrap());
+    let _ = std::fs::remove_file(&outbox_path);
+}
+
+#[test]
+fn test_event_visibility() {
+    use utils::client::ClientInfo;
+    let record = ClientInfoRecord::new(ClientInfo::builder(1, "umoho", &Some("password".to_owned())), 60);
+    let event = ClientEvent::new(EventKind::Changed, &record);
+    assert!(event.is_visible_with("password"));
+    assert!(!event.is_visible_with("wrong"));
+    /* Nobody proves an account without a password. */
+    let record = ClientInfoRecord::new(ClientInfo::new(1, "nas"), 60);
+    assert!(!ClientEvent::new(EventKind::Changed, &record).is_visible_with(""));
+}
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use axum::{Json, http::StatusCode, response::IntoResponse};
+use chrono::Utc;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::storage;
+
+/// The path telling whether the process is alive.
+pub(crate) const PATH_TO_HEALTHZ: &str = "/healthz";
+
+/// The path telling whether the server can take requests.
+pub(crate) const PATH_TO_READYZ: &str = "/readyz";
+
+/// How long the cleaning thread may go without a successful round before it is taken
+/// as stopped. Seconds. It cleans at least every 10 seconds.
+const CLEANER_SILENCE_LIMIT: i64 = 60;
+
+/// When the cleaning thread finished its latest successful round. Unix seconds, 0 before the first.
+static CLEANER_BEAT: AtomicI64 = AtomicI64::new(0);
+
+/// Tell that the cleaning thread finished a round successfully.
+pub(crate) fn beat_cleaner() {
+    CLEANER_BEAT.store(Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    Failing,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    /// Why it fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { name, status: Status::Ok, message: None },
+            Err(message) => Self { name, status: Status::Failing, message: Some(message) },
+        }
+    }
+}
+
+/// The report of the health checks.
+#[derive(Serialize, Debug, ToSchema)]
+pub(crate) struct Report {
+    status: Status,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Failing if any check fails.
+    fn new(checks: Vec<Check>) -> Self {
+        let status = if checks.iter().all(|c| c.status == Status::Ok) { Status::Ok } else { Status::Failing };
+        Self { status, checks }
+    }
+
+    fn into_response(self) -> impl IntoResponse {
+        let code = match self.status {
+            Status::Ok => StatusCode::OK,
+            Status::Failing => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (code, Json(self))
+    }
+}
+
+/// The liveness method. Answering is enough.
+#[utoipa::path(
+    get, path = "/healthz", tag = "operations",
+    responses((status = 200, description = "The process is alive.", body = Report))
+)]
+pub(crate) async fn get_healthz() -> impl IntoResponse {
+    Report::new(vec![]).into_response()
+}
+
+/// The readiness method: the storage is opened and writable, the cleaning thread is running,
+/// and the latest write of the records succeeded. `503` if any of them fails.
+#[utoipa::path(
+    get, path = "/readyz", tag = "operations",
+    responses(
+        (status = 200, description = "Every check passes.", body = Report),
+        (status = 503, description = "A check fails.", body = Report),
+    )
+)]
+pub(crate) async fn get_readyz() -> impl IntoResponse {
+    /* The checks take the storage lock and touch the disk. */
+    let (writable, persisted) = tokio::task::spawn_blocking(storage::check_health).await
+        .unwrap_or_else(|_| (Err("Checking the storage panicked.".to_owned()), Err("Unknown.".to_owned())));
+    Report::new(vec![
+        Check::new("storage", writable),
+        Check::new("cleaner", check_cleaner(CLEANER_BEAT.load(Ordering::Relaxed), Utc::now().timestamp())),
+        Check::new("persistence", persisted),
+    ]).into_response()
+}
+
+fn check_cleaner(beat: i64, now: i64) -> Result<(), String> {
+    if beat == 0 {
+        Err("The cleaning thread has not cleaned successfully yet.".to_owned())
+    }
+    else if now - beat > CLEANER_SILENCE_LIMIT {
+        Err(format!("The cleaning thread has not cleaned successfully for {} second(s).", now - beat))
+    }
+    else {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_report() {
+    assert!(check_cleaner(0, 100).is_err());
+    assert!(check_cleaner(100, 100 + CLEANER_SILENCE_LIMIT).is_ok());
+    assert!(check_cleaner(100, 101 + CLEANER_SILENCE_LIMIT).is_err());
+    let report = Report::new(vec![Check::new("storage", Ok(())), Check::new("cleaner", Err("Stopped.".to_owned()))]);
+    assert_eq!(
+        serde_json::to_string(&report).unwrap(),
+        r#"{"status":"failing","checks":[{"name":"storage","status":"ok"},{"name":"cleaner","status":"failing","message":"Stopped."}]}"#
+    );
+}
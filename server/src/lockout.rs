@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use ipnet::Ipv6Net;
+use tracing::warn;
+
+use crate::metrics::METRICS;
+use crate::ratelimit::LimitBy;
+
+/// Wrong passwords for an account before it is locked out.
+const ACCOUNT_FREE_FAILURES: u32 = 5;
+
+/// Wrong passwords from an address before it is locked out. It may guess many accounts.
+const IP_FREE_FAILURES: u32 = 20;
+
+/// The first lockout, doubled by every wrong password after it.
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+
+/// The longest lockout.
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+
+/// The failures are forgotten after so long without another one.
+const FAILURE_MEMORY: Duration = Duration::from_secs(3600);
+
+/// The most keys kept. Reaching it, the old failures are forgotten, and if there are
+/// still more than half of it, the ones not locked out, and then the least recent.
+const MAX_KEYS: usize = 100_000;
+
+/// The prefix length of the IPv6 networks counted as one address, since a host
+/// usually has a whole /64 to pick its addresses from.
+const IPV6_PREFIX_LEN: u8 = 64;
+
+/// The wrong passwords of the whole server.
+static GUARD: LazyLock<Guard> = LazyLock::new(Guard::new);
+
+/// The wrong passwords of one key.
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts the wrong passwords by account and by source address, and locks them out
+/// for longer and longer. The account is counted from every address, so guessing from
+/// many addresses is stopped too. The price is that anyone can lock an account out
+/// with a few wrong passwords, and keep it locked out for up to an hour by trying again
+/// as each lockout ends; the access rules can keep the strangers from trying at all.
+struct Guard {
+    failures: Mutex<HashMap<(LimitBy, String), Failures>>,
+}
+
+impl Guard {
+    fn new() -> Self {
+        Self { failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// How long the account or the address is still locked out, the longer of them.
+    fn check(&self, account: &str, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().expect("Lockout lock poisoned.");
+        [(LimitBy::Account, account.to_owned()), (LimitBy::Ip, ip_key(ip))].iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .filter(|left| !left.is_zero())
+            .max()
+    }
+
+    /// Count a wrong password. Return the keys locked out by it, with how long.
+    fn fail(&self, account: &str, ip: IpAddr, now: Instant) -> Vec<(LimitBy, String, u32, Duration)> {
+        let mut failures = self.failures.lock().expect("Lockout lock poisoned.");
+        if failures.len() >= MAX_KEYS {
+            prune(&mut failures, now);
+        }
+        let mut locked = vec![];
+        for (by, key, free) in [(LimitBy::Account, account.to_owned(), ACCOUNT_FREE_FAILURES), (LimitBy::Ip, ip_key(ip), IP_FREE_FAILURES)] {
+            let entry = failures.entry((by, key.clone())).or_insert(Failures { count: 0, last: now, locked_until: None });
+            if now.saturating_duration_since(entry.last) >= FAILURE_MEMORY {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last = now;
+            if let Some(lockout) = lockout_of(entry.count, free) {
+                entry.locked_until = Some(now + lockout);
+                locked.push((by, key, entry.count, lockout));
+            }
+        }
+        locked
+    }
+
+    /// Forget the wrong passwords of the account, after a right one. The address keeps
+    /// its count, or a guesser could reset it with the password of its own account.
+    fn succeed(&self, account: &str) {
+        let mut failures = self.failures.lock().expect("Lockout lock poisoned.");
+        failures.remove(&(LimitBy::Account, account.to_owned()));
+    }
+}
+
+/// Make room in the failures, at least half of `MAX_KEYS`, so it is not done again soon.
+fn prune(failures: &mut HashMap<(LimitBy, String), Failures>, now: Instant) {
+    failures.retain(|_, f| now.saturating_duration_since(f.last) < FAILURE_MEMORY);
+    if failures.len() > MAX_KEYS / 2 {
+        /* Flooded by many addresses. They could clear the counts of the others this way, but
+        not the lockouts. */
+        failures.retain(|_, f| f.locked_until.is_some_and(|until| until > now));
+    }
+    if failures.len() > MAX_KEYS / 2 {
+        let mut lasts: Vec<Instant> = failures.values().map(|f| f.last).collect();
+        lasts.sort_unstable();
+        let kept_from = lasts[lasts.len() - MAX_KEYS / 2];
+        failures.retain(|_, f| f.last >= kept_from);
+    }
+}
+
+/// The key an address is counted by: an IPv4 address itself, or the /64 of an IPv6 one.
+fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => Ipv6Net::new(v6, IPV6_PREFIX_LEN).expect("The prefix length is valid.").trunc().to_string(),
+    }
+}
+
+/// The lockout after `count` wrong passwords, `free` of which are allowed.
+fn lockout_of(count: u32, free: u32) -> Option<Duration> {
+    let over = count.checked_sub(free)?;
+    /* Stop doubling long before overflowing. */
+    let lockout = BASE_LOCKOUT.saturating_mul(1 << over.min(20));
+    Some(lockout.min(MAX_LOCKOUT))
+}
+
+/// How long a password may not be tried for the account from the address.
+pub(crate) fn locked_out(account: &str, ip: IpAddr) -> Option<Duration> {
+    GUARD.check(account, ip, Instant::now())
+}
+
+/// Count a wrong password for the account from the address, and audit the lockouts it causes.
+pub(crate) fn fail(account: &str, ip: IpAddr) {
+    for (by, key, failures, lockout) in GUARD.fail(account, ip, Instant::now()) {
+        METRICS.observe_lockout(&by.to_string());
+        warn!(
+            target: "audit", event = "lockout", %by, %key, %account, %ip, failures, lockout_seconds = lockout.as_secs(),
+            "Locked out after wrong passwords."
+        );
+    }
+}
+
+/// Forget the wrong passwords of the account, after a right one.
+pub(crate) fn succeed(account: &str) {
+    GUARD.succeed(account)
+}
+
+#[test]
+fn test_lockout() {
+    assert_eq!(lockout_of(4, 5), None);
+    assert_eq!(lockout_of(5, 5), Some(Duration::from_secs(2)));
+    assert_eq!(lockout_of(7, 5), Some(Duration::from_secs(8)));
+    assert_eq!(lockout_of(u32::MAX, 5), Some(MAX_LOCKOUT));
+
+    let guard = Guard::new();
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let start = Instant::now();
+    for _ in 1..ACCOUNT_FREE_FAILURES {
+        assert!(guard.fail("umoho", ip, start).is_empty());
+    }
+    assert_eq!(guard.check("umoho", ip, start), None);
+    let locked = guard.fail("umoho", ip, start);
+    assert_eq!(locked.len(), 1);
+    assert_eq!(locked[0].0, LimitBy::Account);
+    assert_eq!(guard.check("umoho", ip, start), Some(BASE_LOCKOUT));
+    /* Another account from the same address is not locked out yet. */
+    assert_eq!(guard.check("nas", ip, start), None);
+    assert_eq!(guard.check("umoho", ip, start + BASE_LOCKOUT), None);
+    /* Doubled by the next one. */
+    guard.fail("umoho", ip, start + BASE_LOCKOUT);
+    assert_eq!(guard.check("umoho", ip, start + BASE_LOCKOUT), Some(BASE_LOCKOUT * 2));
+    guard.succeed("umoho");
+    assert_eq!(guard.check("umoho", ip, start + BASE_LOCKOUT), None);
+    /* The address is still counted. */
+    let failures = guard.failures.lock().unwrap();
+    assert!(!failures.contains_key(&(LimitBy::Account, "umoho".to_owned())));
+    assert_eq!(failures[&(LimitBy::Ip, ip.to_string())].count, ACCOUNT_FREE_FAILURES + 1);
+    drop(failures);
+
+    /* The addresses of an IPv6 /64 are counted together. */
+    let (a, b): (IpAddr, IpAddr) = ("2001:db8::1".parse().unwrap(), "2001:db8::2:1".parse().unwrap());
+    assert_eq!(ip_key(a), "2001:db8::/64");
+    for i in 0..IP_FREE_FAILURES {
+        guard.fail(&format!("guess-{}", i), if i % 2 == 0 { a } else { b }, start);
+    }
+    assert_eq!(guard.check("another", b, start), Some(BASE_LOCKOUT));
+    assert_eq!(guard.check("another", "2001:db8:0:1::1".parse().unwrap(), start), None);
+
+    /* Too many keys: the ones not locked out are forgotten first. */
+    let guard = Guard::new();
+    for i in 0..MAX_KEYS as u32 {
+        guard.fail("umoho", IpAddr::from(i.to_be_bytes()), start);
+    }
+    assert!(guard.failures.lock().unwrap().len() <= MAX_KEYS / 2 + 2);
+    assert!(guard.check("umoho", ip, start).is_some());
+}
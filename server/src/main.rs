@@ -1,15 +1,31 @@
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use storage::ClientInfoRecord;
-use tinydb::Database;
-use tinydb::error::DatabaseError;
+use clap::Parser;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
 use serde_derive::{Serialize, Deserialize};
+use tracing::{debug, error, info, warn};
+use utils::config::{self, ConfigLoader, FieldError, Validate};
+use utils::logging;
 
-use crate::restful::{DEFAULT_LIFETIME, DATABASE_DUMPS_PATH};
-use crate::storage::clean_outdated;
+use crate::access::AccessRule;
+use crate::cli::{Cli, Command};
+use crate::events::{ClientEvent, EventHub, EventKind};
+use crate::metrics::METRICS;
+use crate::ratelimit::{RateLimitConfig, default_rate_limits};
+use crate::reload::LiveConfig;
+use crate::restful::DEFAULT_LIFETIME;
+use crate::storage::{CHANGES, clean_outdated};
+use crate::validation::AddressPolicy;
+use crate::webhook::{WebhookConfig, WebhookDispatcher, WEBHOOK_OUTBOX_PATH};
+
+/// About the cmdline arguments.
+mod cli;
 
 /// About the RESTful API server.
 mod restful;
@@ -17,6 +33,48 @@ mod restful;
 /// About the database and storages.
 mod storage;
 
+/// About the durable files of the records: the write-ahead log and the snapshots.
+mod persist;
+
+/// About the events of the client information records.
+mod events;
+
+/// About delivering the events to the webhooks.
+mod webhook;
+
+/// About watching the events by streaming.
+mod watch;
+
+/// About reloading the config while running.
+mod reload;
+
+/// About the Prometheus metrics.
+mod metrics;
+
+/// About the health and readiness checks.
+mod health;
+
+/// About the OpenAPI document of the API.
+mod openapi;
+
+/// About the problem details answered for the errors.
+mod problem;
+
+/// About limiting the rate of the requests.
+mod ratelimit;
+
+/// About locking out the guessers of the passwords.
+mod lockout;
+
+/// About validating the posted client information.
+mod validation;
+
+/// About allowing and denying the networks of the requests.
+mod access;
+
+/// About resolving the client addresses behind the trusted proxies.
+mod proxy;
+
 /// Cleaning frequent, usually very short. Seconds.
 const CLEAN_FREQUENT: f64 = 0.5;
 
@@ -26,108 +84,266 @@ const FINISHED_CLEAR_RELAX_DELAY: f64 = 10.0;
 /// Delay when error to clean. Seconds.
 const ERROR_TO_CLEAN_DELAY: f64 = 10.0;
 
+/// How long the requests in flight may take to finish when shutting down. Seconds.
+const DRAIN_TIMEOUT: u64 = 10;
+
 /// Default config file put at this path.
 const DEFAULT_CONFIG_PATH: &str = "./server.conf.toml";
 
+/// The base name of the config files in the config directories.
+const CONFIG_NAME: &str = "server";
+
+/// The bounds of the record lifetime in the config. Seconds.
+const LIFETIME_RANGE: std::ops::RangeInclusive<u64> = 1..=86400;
+
 #[derive(Serialize, Deserialize)]
 struct Config {
+    /// Needs a restart to change.
     bind: String,
+    /// How long a posted record lives. Seconds.
+    #[serde(default = "default_lifetime")]
+    lifetime: u64,
+    /// Post the events to these URLs.
+    #[serde(default)]
+    webhooks: Vec<WebhookConfig>,
+    /// Serve the metrics on this admin address instead of `bind`. Needs a restart to change.
+    #[serde(default)]
+    metrics_bind: Option<String>,
+    /// The token buckets of the endpoints. Replace the default ones if any is set.
+    #[serde(default = "default_rate_limits")]
+    rate_limits: Vec<RateLimitConfig>,
+    /// Which addresses the posts may carry.
+    #[serde(default)]
+    addresses: AddressPolicy,
+    /// Which networks may call the endpoints.
+    #[serde(default)]
+    access: Vec<AccessRule>,
+    /// The proxies whose `X-Forwarded-For` tells the client address. CIDRs, or single addresses.
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+}
+
+fn default_lifetime() -> u64 {
+    DEFAULT_LIFETIME
+}
+
+impl Validate for Config {
+    const KEYS: &'static [&'static str] = &["bind", "lifetime", "webhooks", "metrics_bind", "rate_limits", "addresses", "access", "trusted_proxies"];
+
+    fn validate(&self, errors: &mut Vec<FieldError>) {
+        errors.extend(config::check_socket_addr("bind", &self.bind));
+        if let Some(metrics_bind) = &self.metrics_bind {
+            errors.extend(config::check_socket_addr("metrics_bind", metrics_bind));
+            if *metrics_bind == self.bind {
+                errors.push(
+                    FieldError::new("metrics_bind", "the admin address is the same as `bind`")
+                        .suggest("remove it to serve the metrics on `bind`")
+                );
+            }
+        }
+        errors.extend(config::check_range("lifetime", self.lifetime, LIFETIME_RANGE));
+        for (i, webhook) in self.webhooks.iter().enumerate() {
+            errors.extend(config::check_http_url(&format!("webhooks[{}].url", i), &webhook.url));
+            if webhook.secret.as_deref() == Some("") {
+                errors.push(
+                    FieldError::new(format!("webhooks[{}].secret", i), "the secret is empty")
+                        .suggest("remove it to send unsigned events")
+                );
+            }
+        }
+        ratelimit::validate_rate_limits(&self.rate_limits, errors);
+        self.addresses.validate(errors);
+        access::validate_access(&self.access, errors);
+        proxy::validate_trusted_proxies(&self.trusted_proxies, errors);
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    /* Set the Ctrl-C handler. */
-    ctrlc::set_handler(|| {
-        eprintln!("Server stop.");
-        std::process::exit(0);
-    }).expect("Cannot set Ctrl-C handler.");
+    /* Parse the cmdline arguments. */
+    let cli = Cli::parse();
+    if let Err(e) = logging::init(&cli.log_level, cli.log_format) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    /* Listen to SIGINT and SIGTERM from now on, to shut down gracefully. */
+    let shutdown = shutdown_signal();
+
+    if let Some(Command::CheckConfig) = cli.command {
+        check_config(&cli);
+    }
 
-    /* Load config from the file. */
-    println!("Loading config...");
-    let config = get_config(DEFAULT_CONFIG_PATH).expect("Cannot load config.");
+    /* Load config from the files, the environment variables and the cmdline. */
+    info!("Loading config...");
+    let config = match get_config(&cli) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    /* Recover the records from the snapshot and the WAL. They are created if not exist. */
+    match storage::open() {
+        Ok(count) => info!(count, "Recovered the records."),
+        Err(e) => {
+            error!("Cannot recover the records: {}", e);
+            std::process::exit(1);
+        },
+    }
 
-    /* Whatever, test if the database exists. It will be create if not exist. */
-    Database::<ClientInfoRecord>::auto_from(PathBuf::from(DATABASE_DUMPS_PATH), false).expect("Database error.");
+    /* Open the webhook outbox, and deliver the events in the background. */
+    let webhooks = Arc::new(
+        WebhookDispatcher::new(config.webhooks.clone(), WEBHOOK_OUTBOX_PATH).expect("Cannot open the webhook outbox.")
+    );
+    tokio::spawn({
+        let webhooks = webhooks.clone();
+        async move { webhooks.run().await }
+    });
+    let events = Arc::new(EventHub::new(webhooks.clone()));
 
-    /* The thread of cleaning the outdated storages of client information. */
-    let cleaning = cleaning_thread();
+    /* The thread of cleaning the outdated storages of client information. Dropping the sender stops it. */
+    let (stop_cleaning, cleaning_stopped) = mpsc::channel();
+    let cleaning = cleaning_thread(events.clone(), cleaning_stopped);
+
+    /* Apply the changes of the config files (or SIGHUP) while running. */
+    let bind_addr: SocketAddr = config.bind.parse().expect("The bind address is validated.");
+    let metrics_addr: Option<SocketAddr> = config.metrics_bind.as_ref()
+        .map(|addr| addr.parse().expect("The metrics bind address is validated."));
+    let live = Arc::new(LiveConfig::new(config));
+    tokio::spawn(reload::run_reloader(config_loader(&cli), live.clone(), webhooks.clone()));
 
     /* Start the RESTful API server. Listening on the binding address load from the config. */
-    let bind_addr: SocketAddr = config.bind.parse().expect("Cannot parse the bind address. Please check the config.");
-    println!("Starting the RESTful API server...\nListening on {}...", bind_addr);
-    restful::run_restful_api_server(SocketAddr::from(bind_addr))
-        .await.expect("Cannot run the RESTful server.");
+    info!(%bind_addr, "Starting the RESTful API server...");
+    if let Some(metrics_addr) = metrics_addr {
+        /* Nothing to drain there, it stops with the process. */
+        info!(%metrics_addr, "Starting the metrics server...");
+        tokio::spawn(async move {
+            if let Err(e) = restful::run_metrics_server(metrics_addr).await {
+                error!("Cannot run the metrics server: {}", e);
+            }
+        });
+    }
+    let (stop_serving, serving_stopped) = oneshot::channel::<()>();
+    let mut server = tokio::spawn(restful::run_restful_api_server(
+        bind_addr, metrics_addr.is_none(), events.clone(), live, async { let _ = serving_stopped.await; },
+    ));
+    tokio::select! {
+        result = &mut server => {
+            result.expect("RESTful server task panicked.").expect("Cannot run the RESTful server.");
+        },
+        _ = shutdown => {},
+    }
+
+    /* Stop accepting, end the watches and the long polls, and wait for the others to finish. */
+    info!("Shutting down... Waiting for the requests in flight.");
+    let _ = stop_serving.send(());
+    events.close();
+    CHANGES.close();
+    match tokio::time::timeout(Duration::from_secs(DRAIN_TIMEOUT), &mut server).await {
+        Ok(_) => info!("All the requests finished."),
+        Err(_) => warn!("Some requests are still running after {} second(s), drop them.", DRAIN_TIMEOUT),
+    }
 
-    /* Let this function join main thread. */
+    /* Let the cleaning thread finish its round, then join it. */
+    drop(stop_cleaning);
     cleaning.join().expect("Thread cleaning joining error.");
+
+    /* Wait for the files being written, and keep the others from writing until exit. */
+    let _database = storage::close();
+    webhooks.close();
+    info!("Server stop.");
+}
+
+/// Wait for SIGINT or SIGTERM. The signals are listened to from the call on.
+#[cfg(unix)]
+fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Cannot listen to SIGINT.");
+    let mut terminate = signal(SignalKind::terminate()).expect("Cannot listen to SIGTERM.");
+    async move {
+        tokio::select! {
+            _ = interrupt.recv() => {},
+            _ = terminate.recv() => {},
+        }
+    }
 }
 
-/// Return a `JoinHandle<()>` struct, the spawned thread.
-fn cleaning_thread() -> JoinHandle<()> {
-    thread::spawn(|| {
+/// Wait for Ctrl-C, without the Unix signals.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        error!("Cannot listen to Ctrl-C, stop the server by killing it.");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Return a `JoinHandle<()>` struct, the spawned thread. It returns when `stop` is disconnected.
+fn cleaning_thread(events: Arc<EventHub>, stop: Receiver<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
         loop {
-            match clean_outdated(DEFAULT_LIFETIME) {
-                Ok(_) => {
-                    #[cfg(feature = "debug-printing")] println!("Successfully cleaned outdated storage of client information.");
+            let cleaned = clean_outdated();
+            /* A failing round does not beat, so the readiness check reports it. */
+            if cleaned.is_ok() {
+                health::beat_cleaner();
+            }
+            let delay = match cleaned {
+                Ok(offline) if offline.is_empty() => {
+                    debug!("Finished to clean the outdated storages in the database.");
+                    /* Nothing is outdated now. Have a relax. */
+                    FINISHED_CLEAR_RELAX_DELAY
+                },
+                Ok(offline) => {
+                    METRICS.observe_expiries(offline.len());
+                    debug!(offline = offline.len(), "Successfully cleaned outdated storage of client information.");
+                    /* Tell the others these devices are offline. */
+                    for record in &offline {
+                        events.publish(ClientEvent::new(EventKind::Expired, record));
+                    }
                     /* Have a (very short time) relax. */
-                    thread::sleep(Duration::from_secs_f64(CLEAN_FREQUENT));
-                    continue;
+                    CLEAN_FREQUENT
                 },
                 Err(e) => {
-                    match e {
-                        DatabaseError::ItemNotFound => {
-                            #[cfg(feature = "debug-printing")] println!("Finished to clean the outdated storages in the database.");
-                            /* If we can'n find the item, it's possibly be cleaned. Have a relax. */
-                            thread::sleep(Duration::from_secs_f64(FINISHED_CLEAR_RELAX_DELAY));
-                            continue;
-                        }
-                        _ => {
-                            eprintln!("Failed to clean the outdated storages.");
-                            thread::sleep(Duration::from_secs_f64(ERROR_TO_CLEAN_DELAY));
-                            continue;
-                        },
-                    }
+                    error!("Failed to clean the outdated storages: {}", e);
+                    ERROR_TO_CLEAN_DELAY
                 },
+            };
+            /* Sleep, but wake up at once to stop. */
+            if let Err(RecvTimeoutError::Disconnected) = stop.recv_timeout(Duration::from_secs_f64(delay)) {
+                break;
             }
         }
     })
 }
 
-/// Read the config file at `path`, or create a new one
-/// by default config if the file not exists.
-fn get_config(path: &str) -> Result<Config, anyhow::Error> {
-    use std::fs::File;
-    use std::io::Write;
-
-    use std::io::{stdin, stdout, Read};
-    /* Try to open the config file. Create a new one if it is not exists. */
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => {
-            /* Read config from the cmdline. */
-            print!("Please input the bind (example: 127.0.0.1:8080): ");
-            stdout().flush()?;
-            let mut bind = String::new();
-            stdin().read_line(&mut bind)?;
-            let bind = bind.trim().to_owned();
-
-            /* Build a new config by default. */
-            let default_config = Config {
-                bind
-            };
-
-            /* Create a file and write contents. */
-            let mut new_file = File::create(path)?;
-            let buf = toml::to_vec(&default_config)?;
-            new_file.write(&buf)?;
+/// Load the config from the layers: the config files, the `HERE_*` environment variables,
+/// then the cmdline flags. Ask for the missing fields only if the cmdline is a terminal.
+fn get_config(cli: &Cli) -> Result<Config, anyhow::Error> {
+    let config = config_loader(cli).load()?;
+    Ok(config)
+}
 
-            /* Return the default config. */
-            return Ok(default_config);
+/// Validate the config without asking for anything, report the result, then exit.
+fn check_config(cli: &Cli) -> ! {
+    let loader = config_loader(cli);
+    for path in loader.existing_files() {
+        println!("Read {}", path.display());
+    }
+    match loader.check::<Config>() {
+        Ok(_) => {
+            println!("The config is valid.");
+            std::process::exit(0);
         },
-    };
-    /* Read the config from the file. */
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let config = toml::from_str(&contents)?;
-    Ok(config)
-}
\ No newline at end of file
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// The config layers of the server.
+fn config_loader(cli: &Cli) -> ConfigLoader {
+    ConfigLoader::new(CONFIG_NAME, &cli.config)
+        .field("bind", "Please input the bind (example: 127.0.0.1:8080): ", true)
+        .set_override("bind", cli.bind.clone())
+}
@@ -0,0 +1,154 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use utils::server::PresenceState;
+
+use crate::storage;
+
+/// The path of the metrics, on the API address or on the admin address.
+pub(crate) const PATH_TO_METRICS: &str = "/metrics";
+
+/// The route label of the requests which match no route, so unknown paths do not make new series.
+pub(crate) const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// The metrics of the server, in the Prometheus text format.
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    auth_failures: IntCounterVec,
+    rate_limited: IntCounterVec,
+    lockouts: IntCounterVec,
+    access_denied: IntCounterVec,
+    /// Counted when rendering, from the storage.
+    records: IntGaugeVec,
+    accounts: IntGauge,
+    expiries: IntCounter,
+    persist_duration: HistogramVec,
+    persist_failures: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("here".to_owned()), None).expect("The metric prefix is valid.");
+        let metrics = Self {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "The HTTP requests finished."),
+                &["route", "method", "status"],
+            ).expect("The metric is valid."),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "How long the HTTP requests took, waiting included."),
+                &["route", "method"],
+            ).expect("The metric is valid."),
+            auth_failures: IntCounterVec::new(
+                Opts::new("auth_failures_total", "The requests refused for a wrong password."),
+                &["route"],
+            ).expect("The metric is valid."),
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "The requests refused for going over a rate limit."),
+                &["route", "by"],
+            ).expect("The metric is valid."),
+            lockouts: IntCounterVec::new(
+                Opts::new("lockouts_total", "The lockouts of the accounts and the addresses after wrong passwords."),
+                &["by"],
+            ).expect("The metric is valid."),
+            access_denied: IntCounterVec::new(
+                Opts::new("access_denied_total", "The requests refused for coming from a network not allowed."),
+                &["route"],
+            ).expect("The metric is valid."),
+            records: IntGaugeVec::new(
+                Opts::new("records", "The records by presence state."),
+                &["state"],
+            ).expect("The metric is valid."),
+            accounts: IntGauge::new("accounts", "The accounts which have a record.").expect("The metric is valid."),
+            expiries: IntCounter::new("expiries_total", "The records which went offline by expiring.")
+                .expect("The metric is valid."),
+            persist_duration: HistogramVec::new(
+                HistogramOpts::new("persist_duration_seconds", "How long writing the records to the disk took.")
+                    .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+                &["op"],
+            ).expect("The metric is valid."),
+            persist_failures: IntCounterVec::new(
+                Opts::new("persist_failures_total", "The failures of writing the records to the disk."),
+                &["op"],
+            ).expect("The metric is valid."),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.requests.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.request_duration.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.auth_failures.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.rate_limited.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.lockouts.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.access_denied.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.records.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.accounts.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.expiries.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.persist_duration.clone())).expect("The metric is registered once.");
+        metrics.registry.register(Box::new(metrics.persist_failures.clone())).expect("The metric is registered once.");
+        metrics
+    }
+
+    /// Count a finished request. `route` is the matched route, not the path, to keep the series few.
+    pub(crate) fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        self.requests.with_label_values(&[route, method, &status.to_string()]).inc();
+        self.request_duration.with_label_values(&[route, method]).observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn observe_auth_failure(&self, route: &str) {
+        self.auth_failures.with_label_values(&[route]).inc();
+    }
+
+    /// Count a refused request, `by` being `account` or `ip`.
+    pub(crate) fn observe_rate_limited(&self, route: &str, by: &str) {
+        self.rate_limited.with_label_values(&[route, by]).inc();
+    }
+
+    /// Count a lockout, `by` being `account` or `ip`.
+    pub(crate) fn observe_lockout(&self, by: &str) {
+        self.lockouts.with_label_values(&[by]).inc();
+    }
+
+    pub(crate) fn observe_access_denied(&self, route: &str) {
+        self.access_denied.with_label_values(&[route]).inc();
+    }
+
+    pub(crate) fn observe_expiries(&self, count: usize) {
+        self.expiries.inc_by(count as u64);
+    }
+
+    /// Count a write of the records, `op` being `append` or `snapshot`.
+    pub(crate) fn observe_persist(&self, op: &str, elapsed: Duration, ok: bool) {
+        self.persist_duration.with_label_values(&[op]).observe(elapsed.as_secs_f64());
+        if !ok {
+            self.persist_failures.with_label_values(&[op]).inc();
+        }
+    }
+
+    /// Count the records, then encode all the metrics.
+    pub(crate) fn render(&self) -> String {
+        let states = storage::count_presence();
+        for state in [PresenceState::Online, PresenceState::Stale, PresenceState::Offline] {
+            let count = states.iter().filter(|s| **s == state).count();
+            self.records.with_label_values(&[&state.to_string()]).set(count as i64);
+        }
+        self.accounts.set(states.len() as i64);
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).expect("Encoding the metrics cannot fail.");
+        String::from_utf8(buffer).expect("The metrics are UTF-8.")
+    }
+}
+
+#[test]
+fn test_render_names() {
+    let metrics = Metrics::new();
+    metrics.observe_request("/here/server", "GET", 200, Duration::from_millis(3));
+    metrics.observe_persist("append", Duration::from_millis(1), false);
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer).unwrap();
+    let text = String::from_utf8(buffer).unwrap();
+    assert!(text.contains(r#"here_http_requests_total{method="GET",route="/here/server",status="200"} 1"#));
+    assert!(text.contains(r#"here_persist_failures_total{op="append"} 1"#));
+}
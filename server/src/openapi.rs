@@ -0,0 +1,54 @@
+use std::sync::LazyLock;
+
+use utoipa::OpenApi;
+
+use crate::{health, restful, watch};
+
+/// The API, described from the handlers and the types they take and answer.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Here",
+        description = "Clients post their addresses, and the others look them up by account. \
+            The endpoints of `/here/v1` are served under `/here` without the version too, for the clients from before the versions. \
+            A client may send its version in the `X-Here-Client-Version` header or its `User-Agent`, and is refused with `400` if it is not supported.",
+    ),
+    paths(
+        restful::get_discovery,
+        restful::get_openapi,
+        restful::get_server_info,
+        restful::get_client_info,
+        restful::post_client_info,
+        restful::deregister_client_info,
+        watch::watch_client_info_sse,
+        watch::watch_client_info_ws,
+        health::get_healthz,
+        health::get_readyz,
+        restful::get_metrics,
+    ),
+    tags(
+        (name = "discovery", description = "What the server offers."),
+        (name = "clients", description = "Posting and looking up the addresses of the clients."),
+        (name = "watching", description = "Streaming the changes of the clients."),
+        (name = "operations", description = "Health checks and metrics."),
+    ),
+)]
+struct ApiDoc;
+
+/// The OpenAPI document as JSON, generated once.
+pub(crate) fn document() -> &'static str {
+    static DOCUMENT: LazyLock<String> = LazyLock::new(|| {
+        ApiDoc::openapi().to_pretty_json().expect("The OpenAPI document is serializable.")
+    });
+    &DOCUMENT
+}
+
+#[test]
+fn test_document_matches_routes() {
+    let documented: std::collections::BTreeSet<String> = ApiDoc::openapi().paths.paths.into_keys().collect();
+    let routed: std::collections::BTreeSet<String> = restful::documented_paths().into_iter().collect();
+    assert_eq!(documented, routed);
+    /* The types are described. */
+    let document: serde_json::Value = serde_json::from_str(document()).unwrap();
+    assert!(document["components"]["schemas"]["GetClientInfoResponse"]["properties"]["presence"].is_object());
+}
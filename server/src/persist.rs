@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bincode::Options;
+use chrono::{DateTime, Utc, serde::ts_seconds};
+use serde_derive::{Serialize, Deserialize};
+use tracing::{error, warn};
+use utils::client::ClientInfo;
+
+use crate::metrics::METRICS;
+use crate::storage::ClientInfoRecord;
+
+/// Write a snapshot and empty the WAL after so many WAL entries.
+const COMPACT_EVERY: u64 = 1024;
+
+/// How long the result of probing the snapshot directory is kept, so the frequent
+/// readiness checks do not touch the disk under the storage lock each time. Seconds.
+const PROBE_KEPT: u64 = 5;
+
+/// A change of the records. Appended to the WAL before it is applied. Externally tagged,
+/// since the buffering of the other representations cannot hold the `u128` ids.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Mutation {
+    /// Put the record of an account, replacing the former one.
+    Put { record: ClientInfoRecord },
+    Remove { account: String },
+}
+
+/// A line of the WAL.
+#[derive(Serialize, Deserialize, Debug)]
+struct WalEntry {
+    /// Increases by one for each entry, and goes on across the snapshots.
+    seq: u64,
+    op: Mutation,
+}
+
+/// The records at a point of the WAL.
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    /// The last WAL entry included.
+    seq: u64,
+    records: Vec<ClientInfoRecord>,
+}
+
+/// A record of the legacy tinydb database, in the layout the former versions wrote.
+/// bincode keeps no field names, so it must not follow `ClientInfoRecord`.
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Debug)]
+struct LegacyRecord {
+    client_info: ClientInfo,
+    #[serde(with = "ts_seconds")]
+    record_time: DateTime<Utc>,
+    lifetime: u64,
+}
+
+impl From<LegacyRecord> for ClientInfoRecord {
+    fn from(legacy: LegacyRecord) -> Self {
+        ClientInfoRecord::new(legacy.client_info, legacy.lifetime).set_record_time(legacy.record_time)
+    }
+}
+
+/// The records of the accounts, kept in memory and made durable by an fsynced
+/// append-only WAL and compacted snapshots.
+pub(crate) struct Store {
+    records: HashMap<String, ClientInfoRecord>,
+    /// The last WAL entry applied.
+    seq: u64,
+    wal: File,
+    /// How many entries the WAL has after the snapshot.
+    wal_entries: u64,
+    snapshot_path: PathBuf,
+    /// Why the latest write failed. Cleared by a write which succeeds.
+    last_failure: Option<String>,
+    /// When the snapshot directory was probed, and the result.
+    probed: Option<(Instant, Result<(), String>)>,
+}
+
+impl Store {
+    /// Recover the records: load the snapshot (or the records of a `legacy_path` tinydb file
+    /// if there is no snapshot yet), then replay the WAL entries after it. A torn entry at
+    /// the end of the WAL, from a write which never finished, is cut off. A damaged entry
+    /// before the others is skipped, so they are still replayed.
+    pub(crate) fn open(snapshot_path: &Path, wal_path: &Path, legacy_path: &Path) -> io::Result<Self> {
+        let (snapshot, migrated) = match fs::read(snapshot_path) {
+            Ok(bytes) => (serde_json::from_slice::<Snapshot>(&bytes)?, false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && legacy_path.exists() => {
+                (Snapshot { seq: 0, records: read_legacy(legacy_path)? }, true)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Snapshot { seq: 0, records: vec![] }, false),
+            Err(e) => return Err(e),
+        };
+        let mut store = Self {
+            records: snapshot.records.into_iter().map(|r| (r.client_info.account.clone(), r)).collect(),
+            seq: snapshot.seq,
+            wal: OpenOptions::new().create(true).read(true).append(true).open(wal_path)?,
+            wal_entries: 0,
+            snapshot_path: snapshot_path.to_owned(),
+            last_failure: None,
+            probed: None,
+        };
+
+        let mut contents = vec![];
+        store.wal.read_to_end(&mut contents)?;
+        let mut valid_len = 0;
+        for line in contents.split_inclusive(|b| *b == b'\n') {
+            /* An entry without its newline is where the writing stopped. */
+            let entry = match line.strip_suffix(b"\n").map(serde_json::from_slice::<WalEntry>) {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    error!(offset = valid_len, "Skip a damaged entry of the WAL: {}", e);
+                    valid_len += line.len();
+                    continue;
+                },
+                None => break,
+            };
+            valid_len += line.len();
+            /* Already in the snapshot. */
+            if entry.seq <= store.seq {
+                continue;
+            }
+            store.apply(entry.op);
+            store.seq = entry.seq;
+            store.wal_entries += 1;
+        }
+        if valid_len < contents.len() {
+            warn!("Cut off {} byte(s) of an unfinished entry at the end of the WAL.", contents.len() - valid_len);
+            store.wal.set_len(valid_len as u64)?;
+            store.wal.sync_all()?;
+        }
+        if migrated {
+            store.snapshot()?;
+        }
+        Ok(store)
+    }
+
+    pub(crate) fn get(&self, account: &str) -> Option<&ClientInfoRecord> {
+        self.records.get(account)
+    }
+
+    pub(crate) fn records(&self) -> impl Iterator<Item = &ClientInfoRecord> {
+        self.records.values()
+    }
+
+    /// Put the record of an account durably. Return the former record, if any.
+    pub(crate) fn put(&mut self, record: ClientInfoRecord) -> io::Result<Option<ClientInfoRecord>> {
+        self.append(Mutation::Put { record })
+    }
+
+    /// Remove the record of an account durably. Return it, if any.
+    pub(crate) fn remove(&mut self, account: &str) -> io::Result<Option<ClientInfoRecord>> {
+        if !self.records.contains_key(account) {
+            return Ok(None);
+        }
+        self.append(Mutation::Remove { account: account.to_owned() })
+    }
+
+    /// Write all the records to a temporary file, sync it, and rename it over the snapshot.
+    /// Then the WAL is covered by the snapshot, and emptied.
+    pub(crate) fn snapshot(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        let result = self.write_snapshot();
+        METRICS.observe_persist("snapshot", started.elapsed(), result.is_ok());
+        self.last_failure = result.as_ref().err().map(|e| format!("Cannot write the snapshot: {}", e));
+        result
+    }
+
+    /// Why the latest write of the records failed, if it did.
+    pub(crate) fn last_failure(&self) -> Option<&str> {
+        self.last_failure.as_deref()
+    }
+
+    /// Whether a file can be created beside the snapshot, which writing a snapshot needs.
+    /// The result is kept for `PROBE_KEPT` seconds.
+    pub(crate) fn probe_writable(&mut self) -> Result<(), String> {
+        if let Some((probed_at, result)) = &self.probed {
+            if probed_at.elapsed() < Duration::from_secs(PROBE_KEPT) {
+                return result.clone();
+            }
+        }
+        let probe_path = self.snapshot_path.with_extension("probe");
+        let result = File::create(&probe_path)
+            .and_then(|_| fs::remove_file(&probe_path))
+            .map_err(|e| e.to_string());
+        self.probed = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    fn write_snapshot(&mut self) -> io::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            records: self.records.values().cloned().collect(),
+        };
+        write_atomically(&self.snapshot_path, &serde_json::to_vec(&snapshot)?)?;
+        /* A crash before this is fine: the entries up to `seq` are skipped when replaying. */
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        self.wal_entries = 0;
+        Ok(())
+    }
+
+    /// Append a mutation to the WAL and sync it, then apply it. Return the record it replaced.
+    fn append(&mut self, mutation: Mutation) -> io::Result<Option<ClientInfoRecord>> {
+        let entry = WalEntry { seq: self.seq + 1, op: mutation };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let started = Instant::now();
+        let written = self.wal.metadata().map(|m| m.len()).and_then(|wal_len| {
+            self.wal.write_all(&line).and_then(|_| self.wal.sync_data()).inspect_err(|_| {
+                /* Do not leave a part of the entry before the next ones. */
+                let _ = self.wal.set_len(wal_len);
+            })
+        });
+        METRICS.observe_persist("append", started.elapsed(), written.is_ok());
+        self.last_failure = written.as_ref().err().map(|e| format!("Cannot append to the WAL: {}", e));
+        written?;
+        self.seq = entry.seq;
+        self.wal_entries += 1;
+        let former = self.apply(entry.op);
+        if self.wal_entries >= COMPACT_EVERY {
+            /* The WAL still has everything if this fails. */
+            if let Err(e) = self.snapshot() {
+                error!("Cannot write the snapshot: {}", e);
+            }
+        }
+        Ok(former)
+    }
+
+    fn apply(&mut self, mutation: Mutation) -> Option<ClientInfoRecord> {
+        match mutation {
+            Mutation::Put { record } => self.records.insert(record.client_info.account.clone(), record),
+            Mutation::Remove { account } => self.records.remove(&account),
+        }
+    }
+}
+
+/// Read the records of a legacy tinydb file.
+fn read_legacy(path: &Path) -> io::Result<Vec<ClientInfoRecord>> {
+    let bytes = fs::read(path)?;
+    decode_legacy(&bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Cannot read the legacy database: {}", e)))
+}
+
+/// Decode a legacy tinydb file. tinydb dumps its `Database` by bincode: the label,
+/// the save path and the duplicate checking, then the items. The former versions could
+/// leave several records of an account, and the newest one of each is taken.
+fn decode_legacy(bytes: &[u8]) -> bincode::Result<Vec<ClientInfoRecord>> {
+    /* The options of `bincode::deserialize`, but the whole file must be taken. */
+    let (_label, _save_path, _strict_dupes, items): (String, Option<PathBuf>, bool, Vec<LegacyRecord>) =
+        bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes().deserialize(bytes)?;
+    let mut newest: HashMap<String, LegacyRecord> = HashMap::new();
+    for item in items {
+        match newest.get(&item.client_info.account) {
+            Some(kept) if kept.record_time > item.record_time => {},
+            _ => { newest.insert(item.client_info.account.clone(), item); },
+        }
+    }
+    Ok(newest.into_values().map(Into::into).collect())
+}
+
+/// Write `bytes` to a temporary file, sync it, and rename it over `path`. The file at `path`
+/// is either the former one or the new one whole, even if the process stops in between.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(bytes)?;
+        temp.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Sync a directory, so a rename in it is durable.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[test]
+fn test_recover_from_snapshot_and_wal() {
+    let dir = std::env::temp_dir().join(format!("here-test-persist-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (snapshot_path, wal_path, legacy_path) = (dir.join("records.json"), dir.join("records.wal"), dir.join("none.db"));
+    let record = |account: &str| ClientInfoRecord::new(ClientInfo::new(1, account), 60);
+
+    let mut store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    store.put(record("a")).unwrap();
+    store.snapshot().unwrap();
+    store.put(record("b")).unwrap();
+    assert!(store.put(record("b")).unwrap().is_some());
+    store.remove("a").unwrap();
+    drop(store);
+    /* A write which never finished. */
+    OpenOptions::new().append(true).open(&wal_path).unwrap().write_all(b"{\"seq\":5,\"op\":{\"pu").unwrap();
+
+    let mut store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    assert!(store.get("a").is_none());
+    assert!(store.get("b").is_some());
+    assert_eq!(store.seq, 4);
+    /* The torn entry is cut off, so the next ones are kept. */
+    store.put(record("c")).unwrap();
+    drop(store);
+    let mut store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    assert_eq!(store.records().count(), 2);
+    assert!(store.probe_writable().is_ok());
+    fs::remove_dir_all(&dir).unwrap();
+    /* Kept for a while, not probed again at once. */
+    assert!(store.probe_writable().is_ok());
+}
+
+#[test]
+fn test_skip_damaged_wal_entry() {
+    let dir = std::env::temp_dir().join(format!("here-test-damaged-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (snapshot_path, wal_path, legacy_path) = (dir.join("records.json"), dir.join("records.wal"), dir.join("none.db"));
+    let record = |account: &str| ClientInfoRecord::new(ClientInfo::new(1, account), 60);
+
+    let mut store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    store.put(record("a")).unwrap();
+    drop(store);
+    OpenOptions::new().append(true).open(&wal_path).unwrap().write_all(b"{\"seq\":2,\"op\":\n").unwrap();
+    let mut store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    store.put(record("b")).unwrap();
+    drop(store);
+
+    /* The entries after the damaged one are not lost. */
+    let store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    assert!(store.get("a").is_some() && store.get("b").is_some());
+    assert_eq!(store.seq, 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_migrate_legacy_database() {
+    let dir = std::env::temp_dir().join(format!("here-test-legacy-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (snapshot_path, wal_path, legacy_path) = (dir.join("records.json"), dir.join("records.wal"), dir.join("client-info.db"));
+    /* Written by tinydb, as the former versions did. */
+    let record_time = Utc::now() - chrono::Duration::seconds(30);
+    let legacy_record = |account: &str, ip: &str, record_time| LegacyRecord {
+        client_info: ClientInfo::builder(1, account, &Some("password".to_owned())).set_ips(&vec![ip.parse().unwrap()]),
+        record_time,
+        lifetime: 60,
+    };
+    let mut legacy = tinydb::Database::new("client-info", Some(legacy_path.clone()), false);
+    legacy.add_item(legacy_record("umoho", "192.0.2.1", record_time)).unwrap();
+    /* Two records of one account, the newer one first. */
+    legacy.add_item(legacy_record("nas", "192.0.2.2", record_time)).unwrap();
+    legacy.add_item(legacy_record("nas", "192.0.2.3", record_time - chrono::Duration::seconds(60))).unwrap();
+    legacy.dump_db().unwrap();
+
+    let store = Store::open(&snapshot_path, &wal_path, &legacy_path).unwrap();
+    assert_eq!(store.records().count(), 2);
+    let record = store.get("nas").unwrap();
+    assert_eq!(record.client_info.ipv4s, ["192.0.2.2".parse::<std::net::Ipv4Addr>().unwrap()]);
+    assert_eq!(record.presence().record_time, record_time.timestamp());
+    assert!(snapshot_path.exists());
+    /* A file of another layout is refused, not read as garbage. */
+    fs::remove_file(&snapshot_path).unwrap();
+    fs::write(&legacy_path, b"not a database").unwrap();
+    assert!(Store::open(&snapshot_path, &wal_path, &legacy_path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
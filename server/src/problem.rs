@@ -0,0 +1,119 @@
+use std::future::Future;
+use std::time::Duration;
+
+use axum::{http::{Request, StatusCode, header::{CONTENT_TYPE, RETRY_AFTER}}, middleware::Next, response::{IntoResponse, Response}, Json};
+use utils::server::{FieldProblem, Problem, ResponseMessage, PROBLEM_CONTENT_TYPE};
+
+/// The longest body of a plain error taken as the detail of its problem.
+const MAX_DETAIL_LENGTH: usize = 1024;
+
+tokio::task_local! {
+    /// The request being handled, for the problem details.
+    static REQUEST: RequestContext;
+}
+
+/// Who a problem belongs to.
+pub(crate) struct RequestContext {
+    pub(crate) id: String,
+    pub(crate) path: String,
+}
+
+/// Handle a request with its context, so the errors of it can tell its id and path.
+pub(crate) async fn scope<F: Future>(context: RequestContext, handling: F) -> F::Output {
+    REQUEST.scope(context, handling).await
+}
+
+/// An error of a handler, responded as problem details. The code is put in the extensions
+/// of the response too, for the middlewares.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: ResponseMessage,
+    detail: String,
+    errors: Vec<FieldProblem>,
+    /// Sent as `Retry-After`. Seconds.
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub(crate) fn new(status: StatusCode, code: ResponseMessage, detail: impl Into<String>) -> Self {
+        Self { status, code, detail: detail.into(), errors: vec![], retry_after: None }
+    }
+
+    /// Tell which fields of the request are invalid.
+    pub(crate) fn with_errors(mut self, errors: Vec<FieldProblem>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Tell the client how long to wait before retrying. Rounded up to whole seconds.
+    pub(crate) fn retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait.as_secs_f64().ceil().max(1.0) as u64);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut problem = Problem::new(self.code, self.status.as_u16(), &self.detail);
+        problem.errors = self.errors;
+        /* Outside of a request, such as in a test, there is no context. */
+        let _ = REQUEST.try_with(|request| {
+            problem.request_id = Some(request.id.clone());
+            problem.instance = Some(request.path.clone());
+        });
+        let mut resp = (self.status, [(CONTENT_TYPE, PROBLEM_CONTENT_TYPE)], Json(problem)).into_response();
+        resp.extensions_mut().insert(self.code);
+        if let Some(seconds) = self.retry_after {
+            resp.headers_mut().insert(RETRY_AFTER, seconds.into());
+        }
+        resp
+    }
+}
+
+/// Turn the plain errors, such as of an unknown path or a body which cannot be read,
+/// into problem details. Their text becomes the detail.
+pub(crate) async fn plain_errors_to_problems<B>(req: Request<B>, next: Next<B>) -> Response {
+    let resp = next.run(req).await;
+    let status = resp.status();
+    let is_problem = resp.headers().get(CONTENT_TYPE).map(|v| v == PROBLEM_CONTENT_TYPE).unwrap_or(false);
+    if !(status.is_client_error() || status.is_server_error()) || is_problem {
+        return resp;
+    }
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap_or_default();
+    let mut detail = String::from_utf8_lossy(&body).trim().to_owned();
+    if detail.is_empty() {
+        detail = status.canonical_reason().unwrap_or("Unknown error.").to_owned();
+    }
+    if detail.len() > MAX_DETAIL_LENGTH {
+        let end = (0..=MAX_DETAIL_LENGTH).rev().find(|i| detail.is_char_boundary(*i)).unwrap_or(0);
+        detail.truncate(end);
+    }
+    ApiError::new(status, code_of_status(status), detail).into_response()
+}
+
+/// The code of a plain error.
+fn code_of_status(status: StatusCode) -> ResponseMessage {
+    match status {
+        StatusCode::NOT_FOUND => ResponseMessage::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => ResponseMessage::MethodNotAllowed,
+        StatusCode::TOO_MANY_REQUESTS => ResponseMessage::TooManyRequests,
+        s if s.is_client_error() => ResponseMessage::InvalidRequest,
+        _ => ResponseMessage::Internal,
+    }
+}
+
+#[tokio::test]
+async fn test_problem_has_request() {
+    let context = RequestContext { id: "abc".to_owned(), path: "/here/v1/client/get".to_owned() };
+    let resp = scope(context, async {
+        ApiError::new(StatusCode::NOT_FOUND, ResponseMessage::NotFound, "No record.").into_response()
+    }).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(resp.headers()[CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.problem_type, "urn:here:problem:NotFound");
+    assert_eq!(problem.request_id.as_deref(), Some("abc"));
+    assert_eq!(problem.instance.as_deref(), Some("/here/v1/client/get"));
+}
@@ -0,0 +1,103 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::{body::Body, extract::ConnectInfo, http::{HeaderMap, Request}, middleware::Next, response::Response};
+use ipnet::IpNet;
+use utils::config::FieldError;
+
+use crate::access::parse_cidr;
+use crate::reload::LiveConfig;
+
+/// The header a proxy appends the address it got the request from to.
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// The address of the client of a request. Put in the request extensions by `resolve_client_addr`,
+/// for the access rules, the rate limits, the lockouts and the observed addresses.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct ClientAddr(pub(crate) IpAddr);
+
+/// Check the trusted proxies of the config: CIDRs, or single addresses.
+pub(crate) fn validate_trusted_proxies(proxies: &[String], errors: &mut Vec<FieldError>) {
+    for (i, cidr) in proxies.iter().enumerate() {
+        if parse_cidr(cidr).is_none() {
+            errors.push(
+                FieldError::new(format!("trusted_proxies[{}]", i), format!("`{}` is not a CIDR", cidr))
+                    .suggest("use the network of the proxies such as 10.0.0.0/8, or a single address")
+            );
+        }
+    }
+}
+
+/// The client of a request from `peer`. If the peer is a trusted proxy, the `X-Forwarded-For`
+/// addresses are taken from the right, past the trusted proxies: the first other one is
+/// the client. The ones on its left may be made up by the client, so they are never taken.
+fn client_addr(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    /* An IPv4 client of a dual-stack bind comes as an IPv4-mapped IPv6 address. */
+    let mut client = peer.to_canonical();
+    if !is_trusted(&client) {
+        return client;
+    }
+    /* The proxies append to the last header if there are several. */
+    let forwarded: Vec<&str> = headers.get_all(FORWARDED_FOR_HEADER).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        let hop = hop.trim();
+        let ip = match hop.parse::<IpAddr>().or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip())) {
+            Ok(ip) => ip.to_canonical(),
+            /* Garbled by someone, so the last trusted proxy is the best known. */
+            Err(_) => break,
+        };
+        client = ip;
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Put the `ClientAddr` of the request in its extensions. The trusted proxies are read
+/// from the live config, so they can be reloaded.
+pub(crate) async fn resolve_client_addr(mut req: Request<Body>, next: Next<Body>) -> Response {
+    let peer = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip(),
+        None => return next.run(req).await,
+    };
+    let trusted: Vec<IpNet> = match req.extensions().get::<Arc<LiveConfig>>() {
+        Some(live) => live.current().trusted_proxies.iter().filter_map(|cidr| parse_cidr(cidr)).collect(),
+        None => vec![],
+    };
+    let client = client_addr(peer, req.headers(), &trusted);
+    req.extensions_mut().insert(ClientAddr(client));
+    next.run(req).await
+}
+
+#[test]
+fn test_client_addr() {
+    let trusted = ["10.0.0.0/8".parse().unwrap(), "fd00::1/128".parse().unwrap()];
+    let headers = |values: &[&str]| {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(FORWARDED_FOR_HEADER, value.parse().unwrap());
+        }
+        headers
+    };
+    let peer: IpAddr = "10.0.0.1".parse().unwrap();
+    /* The made up address on the left is not taken. */
+    let forwarded = headers(&["192.0.2.9", "198.51.100.7, 10.0.0.2"]);
+    assert_eq!(client_addr(peer, &forwarded, &trusted), "198.51.100.7".parse::<IpAddr>().unwrap());
+    /* An untrusted peer is the client itself. */
+    let stranger: IpAddr = "::ffff:203.0.113.5".parse().unwrap();
+    assert_eq!(client_addr(stranger, &forwarded, &trusted), "203.0.113.5".parse::<IpAddr>().unwrap());
+    /* Without the header, or with a garbled one, the proxy is the best known. */
+    assert_eq!(client_addr(peer, &HeaderMap::new(), &trusted), peer);
+    assert_eq!(client_addr(peer, &headers(&["198.51.100.7, unknown"]), &trusted), peer);
+    assert_eq!(client_addr(peer, &headers(&["[2001:db8::7]:4711"]), &trusted), "2001:db8::7".parse::<IpAddr>().unwrap());
+
+    let mut errors = vec![];
+    validate_trusted_proxies(&["10.0.0.0/8".to_owned(), "proxy".to_owned()], &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].key, "trusted_proxies[1]");
+}
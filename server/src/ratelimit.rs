@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{body::Body, extract::MatchedPath, http::{Method, Request, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use serde_derive::{Serialize, Deserialize};
+use tracing::info;
+use utils::config::{self, FieldError};
+use utils::server::{Endpoint, ResponseMessage};
+
+use crate::metrics::METRICS;
+use crate::problem::ApiError;
+use crate::proxy::ClientAddr;
+use crate::reload::LiveConfig;
+use crate::restful;
+
+/// The bounds of the burst of a rate limit in the config.
+const BURST_RANGE: std::ops::RangeInclusive<u64> = 1..=10_000;
+
+/// The bounds of the requests per minute of a rate limit in the config.
+const PER_MINUTE_RANGE: std::ops::RangeInclusive<u64> = 1..=1_000_000;
+
+/// Forget the full buckets when there are more than this many, they are the same as new ones.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The buckets of the whole server.
+static LIMITER: LazyLock<RateLimiter> = LazyLock::new(RateLimiter::new);
+
+/// What the requests are counted by.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LimitBy {
+    /// The account in the query or the body. Requests without one are not counted.
+    Account,
+    /// The source IP address.
+    Ip,
+}
+
+impl Display for LimitBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitBy::Account => write!(f, "account"),
+            LimitBy::Ip => write!(f, "ip"),
+        }
+    }
+}
+
+/// A token bucket in the server config: `burst` requests at once, refilled by
+/// `per_minute` requests a minute.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    /// The name of the endpoint, as in the discovery document.
+    pub(crate) endpoint: Endpoint,
+    pub(crate) by: LimitBy,
+    pub(crate) burst: u64,
+    pub(crate) per_minute: u64,
+}
+
+/// The limits when none is configured: posting rewrites the records, so it is limited.
+pub(crate) fn default_rate_limits() -> Vec<RateLimitConfig> {
+    vec![
+        RateLimitConfig { endpoint: Endpoint::PostClientInfo, by: LimitBy::Account, burst: 10, per_minute: 60 },
+        RateLimitConfig { endpoint: Endpoint::PostClientInfo, by: LimitBy::Ip, burst: 30, per_minute: 300 },
+    ]
+}
+
+/// Check the rate limits of the config: known endpoints, sizes in range, and one limit
+/// for each endpoint and key.
+pub(crate) fn validate_rate_limits(limits: &[RateLimitConfig], errors: &mut Vec<FieldError>) {
+    for (i, limit) in limits.iter().enumerate() {
+        if limit.endpoint == Endpoint::Unknown {
+            errors.push(
+                FieldError::new(format!("rate_limits[{}].endpoint", i), "unknown endpoint")
+                    .suggest("use an endpoint name of the discovery document, such as `post_client_info`")
+            );
+        }
+        errors.extend(config::check_range(&format!("rate_limits[{}].burst", i), limit.burst, BURST_RANGE));
+        errors.extend(config::check_range(&format!("rate_limits[{}].per_minute", i), limit.per_minute, PER_MINUTE_RANGE));
+        if limits[..i].iter().any(|l| l.endpoint == limit.endpoint && l.by == limit.by) {
+            errors.push(
+                FieldError::new(format!("rate_limits[{}]", i), format!("the endpoint is already limited by {}", limit.by))
+                    .suggest("keep one of them")
+            );
+        }
+    }
+}
+
+/// The tokens left of one key.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When it is full again if nothing is taken.
+    full_at: Instant,
+}
+
+struct RateLimiter {
+    buckets: Mutex<HashMap<(Endpoint, LimitBy, String), Bucket>>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self { buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Take a token for `key` by `limit`. If none is left, `Err` with how long until the next one.
+    /// The limit is read on every call, so a reloaded one applies to the existing buckets at once.
+    fn take(&self, limit: &RateLimitConfig, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned.");
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let burst = limit.burst as f64;
+        let per_second = limit.per_minute as f64 / 60.0;
+        let bucket = buckets.entry((limit.endpoint, limit.by, key.to_owned()))
+            .or_insert(Bucket { tokens: burst, updated: now, full_at: now });
+        let refilled = now.saturating_duration_since(bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        }
+        else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        };
+        bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / per_second);
+        result
+    }
+}
+
+/// The account of a post body. The other fields are read by the handler.
+#[derive(Deserialize)]
+struct BodyAccount {
+    account: String,
+}
+
+/// Refuse the requests over the rate limits of their endpoint with `429` and `Retry-After`.
+/// The limits are read from the live config, so they can be reloaded.
+pub(crate) async fn limit_rate(req: Request<Body>, next: Next<Body>) -> Response {
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        None => return next.run(req).await,
+    };
+    let limits: Vec<RateLimitConfig> = match (restful::endpoint_of_route(&route), req.extensions().get::<Arc<LiveConfig>>()) {
+        (Some(endpoint), Some(live)) => live.current().rate_limits.iter().filter(|l| l.endpoint == endpoint).cloned().collect(),
+        _ => vec![],
+    };
+    if limits.is_empty() {
+        return next.run(req).await;
+    }
+    let ip = req.extensions().get::<ClientAddr>().map(|ClientAddr(ip)| ip.to_string());
+    let (req, accounts) = if limits.iter().any(|l| l.by == LimitBy::Account) {
+        match accounts_of(req).await {
+            Ok(read) => read,
+            Err(e) => return e.into_response(),
+        }
+    }
+    else {
+        (req, vec![])
+    };
+    let now = Instant::now();
+    for limit in &limits {
+        let keys = match limit.by {
+            LimitBy::Account => accounts.as_slice(),
+            LimitBy::Ip => ip.as_slice(),
+        };
+        for key in keys {
+            if let Err(wait) = LIMITER.take(limit, key, now) {
+                METRICS.observe_rate_limited(&route, &limit.by.to_string());
+                info!(by = %limit.by, %key, "Refused a request over the rate limit.");
+                let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+                let detail = format!(
+                    "Too many requests to {} by the {} `{}`, the limit is {} a minute. Retry after {} second(s).",
+                    route, limit.by, key, limit.per_minute, seconds
+                );
+                return ApiError::new(StatusCode::TOO_MANY_REQUESTS, ResponseMessage::TooManyRequests, detail)
+                    .retry_after(wait)
+                    .into_response();
+            }
+        }
+    }
+    next.run(req).await
+}
+
+/// Read the accounts of a request, from its query, or from its body if it is a post.
+/// The body is read, so it is put back for the handler.
+pub(crate) async fn accounts_of(req: Request<Body>) -> Result<(Request<Body>, Vec<String>), ApiError> {
+    if req.method() != Method::POST {
+        let pairs: Vec<(String, String)> = match req.uri().query() {
+            Some(query) => serde_urlencoded::from_str(query).map_err(|e| {
+                ApiError::new(StatusCode::BAD_REQUEST, ResponseMessage::InvalidRequest, format!("Cannot read the query: {}", e))
+            })?,
+            None => vec![],
+        };
+        /* Every `account` of the get requests and `accounts` of the watches, so a repeated
+        param cannot hide the account the handler takes. */
+        let accounts = pairs.into_iter()
+            .flat_map(|(key, value)| match key.as_str() {
+                "account" => vec![value],
+                "accounts" => value.split(',').map(|a| a.trim().to_owned()).collect(),
+                _ => vec![],
+            })
+            .filter(|a| !a.is_empty())
+            .collect();
+        return Ok((req, accounts));
+    }
+    let (parts, body) = req.into_parts();
+    let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+        ApiError::new(StatusCode::BAD_REQUEST, ResponseMessage::InvalidRequest, format!("Cannot read the body: {}", e))
+    })?;
+    /* A body without an account is refused by the handler. */
+    let accounts = serde_json::from_slice::<BodyAccount>(&bytes).map(|b| vec![b.account]).unwrap_or_default();
+    Ok((Request::from_parts(parts, Body::from(bytes)), accounts))
+}
+
+#[test]
+fn test_token_bucket() {
+    let limiter = RateLimiter::new();
+    let mut limit = RateLimitConfig { endpoint: Endpoint::PostClientInfo, by: LimitBy::Account, burst: 2, per_minute: 60 };
+    let start = Instant::now();
+    assert!(limiter.take(&limit, "umoho", start).is_ok());
+    assert!(limiter.take(&limit, "umoho", start).is_ok());
+    assert_eq!(limiter.take(&limit, "umoho", start), Err(Duration::from_secs(1)));
+    /* Other keys have their own buckets. */
+    assert!(limiter.take(&limit, "nas", start).is_ok());
+    /* A token a second. */
+    assert!(limiter.take(&limit, "umoho", start + Duration::from_secs(1)).is_ok());
+    assert!(limiter.take(&limit, "umoho", start + Duration::from_secs(1)).is_err());
+    /* A reloaded limit applies at once. */
+    limit.per_minute = 30;
+    assert_eq!(limiter.take(&limit, "umoho", start + Duration::from_secs(1)), Err(Duration::from_secs(2)));
+}
+
+#[test]
+fn test_validate_rate_limits() {
+    let mut limits = default_rate_limits();
+    let mut errors = vec![];
+    validate_rate_limits(&limits, &mut errors);
+    assert!(errors.is_empty());
+    limits.push(RateLimitConfig { endpoint: Endpoint::PostClientInfo, by: LimitBy::Ip, burst: 0, per_minute: 1 });
+    validate_rate_limits(&limits, &mut errors);
+    let keys: Vec<&str> = errors.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, ["rate_limits[2].burst", "rate_limits[2]"]);
+}
+
+#[tokio::test]
+async fn test_accounts_of() {
+    let req = Request::get("/here/v1/client/get?account=office-nas&accounts=a,b&accounts=c").body(Body::empty()).unwrap();
+    let (_, accounts) = accounts_of(req).await.unwrap();
+    assert_eq!(accounts, ["office-nas", "a", "b", "c"]);
+}
@@ -0,0 +1,148 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tracing::{error, info, warn};
+use utils::config::ConfigLoader;
+
+use crate::Config;
+use crate::webhook::WebhookDispatcher;
+
+/// How often the config files are checked for changes. Seconds.
+const WATCH_INTERVAL: u64 = 2;
+
+/// The config the server is running with. Replaced as a whole when reloaded,
+/// so a request always sees one consistent version of it.
+pub(crate) struct LiveConfig {
+    config: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            config: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    /// The config now.
+    pub(crate) fn current(&self) -> Arc<Config> {
+        self.config.read().expect("Live config lock poisoned.").clone()
+    }
+
+    fn replace(&self, config: Config) {
+        *self.config.write().expect("Live config lock poisoned.") = Arc::new(config);
+    }
+}
+
+/// SIGHUP, asking to reload. Without the Unix signals it never comes,
+/// and only the changes of the config files are reloaded.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Signal,
+}
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> Self {
+        Self { signal: signal(SignalKind::hangup()).expect("Cannot listen to SIGHUP.") }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        self.signal.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Reload the config when a config file changes or on SIGHUP, forever.
+pub(crate) async fn run_reloader(loader: ConfigLoader, live: Arc<LiveConfig>, webhooks: Arc<WebhookDispatcher>) {
+    let mut hangup = Hangup::new();
+    let mut stamps = modified_times(&loader);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                /* Do not reload the same change again. */
+                stamps = modified_times(&loader);
+                info!("Got SIGHUP, reloading the config...");
+            },
+            _ = tokio::time::sleep(Duration::from_secs(WATCH_INTERVAL)) => {
+                let now = modified_times(&loader);
+                if now == stamps {
+                    continue;
+                }
+                stamps = now;
+                info!("The config files changed, reloading the config...");
+            },
+        }
+        reload(&loader, &live, &webhooks);
+    }
+}
+
+/// Load the config again and apply it. An invalid config is reported, and the old one is kept.
+fn reload(loader: &ConfigLoader, live: &LiveConfig, webhooks: &WebhookDispatcher) {
+    let mut new = match loader.check::<Config>() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Keep the old config. {}", e);
+            return;
+        },
+    };
+    let old = live.current();
+    let mut changes = vec![];
+    if new.bind != old.bind {
+        warn!("The bind address changed from {} to {}. Restart the server to apply it.", old.bind, new.bind);
+        /* Still listening on the old one. */
+        new.bind = old.bind.clone();
+    }
+    if new.metrics_bind != old.metrics_bind {
+        warn!("The metrics bind address changed. Restart the server to apply it.");
+        new.metrics_bind = old.metrics_bind.clone();
+    }
+    if new.lifetime != old.lifetime {
+        changes.push(format!("lifetime {} -> {}", old.lifetime, new.lifetime));
+    }
+    if new.webhooks != old.webhooks {
+        changes.push(format!("{} webhook target(s)", new.webhooks.len()));
+        webhooks.set_targets(new.webhooks.clone());
+    }
+    if new.addresses != old.addresses {
+        changes.push("address policy".to_owned());
+    }
+    if new.access != old.access {
+        changes.push(format!("{} access rule(s)", new.access.len()));
+    }
+    if new.trusted_proxies != old.trusted_proxies {
+        changes.push(format!("{} trusted proxy network(s)", new.trusted_proxies.len()));
+    }
+    if new.rate_limits != old.rate_limits {
+        changes.push(format!("{} rate limit(s)", new.rate_limits.len()));
+    }
+    live.replace(new);
+    if changes.is_empty() {
+        info!("Reloaded the config, nothing to apply.");
+    }
+    else {
+        info!("Reloaded the config: {}.", changes.join(", "));
+    }
+}
+
+/// The modified times of the config files. `None` for the files which do not exist.
+fn modified_times(loader: &ConfigLoader) -> Vec<(PathBuf, Option<SystemTime>)> {
+    loader.file_paths().into_iter()
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
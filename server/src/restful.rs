@@ -1,19 +1,81 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{future::Future, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
 
-use axum::{Router, routing::{get, post}, response::IntoResponse, http::StatusCode, Json, extract::Query};
-use tinydb::{Database, error::DatabaseError};
-use utils::{AppInfo, server::{GetClientInfoParams, PostClientInfoResponse, ResponseMessage, GetClientInfoResponse}, client::ClientInfo};
+use axum::{Router, routing::{get, post}, response::{IntoResponse, Response}, http::{Request, StatusCode, HeaderMap, HeaderValue, header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH, USER_AGENT}}, Json, Extension, extract::{Query, MatchedPath}, middleware::{self, Next}};
+use tokio::time::{Instant, timeout_at};
+use tracing::{Instrument, debug, info, info_span, warn};
+use utils::{AppInfo, server::{ApiVersion, Capability, Discovery, Endpoint, GetClientInfoParams, PostClientInfoResponse, Problem, ResponseMessage, GetClientInfoResponse}, client::ClientInfo};
+use utils::logging::redact_query;
+use utils::version::{self, CLIENT_VERSION_HEADER, Compatibility, client_version_in_user_agent};
 
-use crate::storage::ClientInfoRecord;
+use crate::events::{ClientEvent, EventHub, EventKind};
+use crate::lockout;
+use crate::health::{PATH_TO_HEALTHZ, PATH_TO_READYZ, get_healthz, get_readyz};
+use crate::metrics::{METRICS, PATH_TO_METRICS, UNMATCHED_ROUTE};
+use crate::openapi;
+use crate::problem::{self, ApiError, RequestContext, plain_errors_to_problems};
+use crate::proxy::{ClientAddr, resolve_client_addr};
+use crate::access::check_access;
+use crate::ratelimit::limit_rate;
+use crate::validation::{check_client_info, limit_body};
+use crate::reload::LiveConfig;
+use crate::storage::{CHANGES, ClientInfoRecord, Deregistered, addresses_differ, deregister_record, find_record, record_version, replace_record};
+use crate::watch::{watch_client_info_sse, watch_client_info_ws};
 
-/// The API path to get server information.
-const PATH_TO_GET_SERVER_INFO: &str = "/here/server";
+/// The root of the API, where the discovery document is.
+const API_ROOT: &str = "/here";
 
-/// The API path to get client information.
-const PATH_TO_GET_CLIENT_INFO: &str = "/here/client/get";
+/// The API version served, under the root. The endpoints are served under the root
+/// without a version too, for the clients from before the versions.
+const API_VERSION: &str = "v1";
 
-/// The API path to post client information.
-const PATH_TO_POST_CLIENT_INFO: &str = "/here/client/post";
+/// The API path to get server information, under the version.
+const PATH_TO_GET_SERVER_INFO: &str = "server";
+
+/// The API path to get client information, under the version.
+const PATH_TO_GET_CLIENT_INFO: &str = "client/get";
+
+/// The API path to post client information, under the version.
+const PATH_TO_POST_CLIENT_INFO: &str = "client/post";
+
+/// The API path to take the client information offline at once, under the version.
+const PATH_TO_DEREGISTER_CLIENT_INFO: &str = "client/deregister";
+
+/// The API path to watch client information by server-sent events, under the version.
+const PATH_TO_WATCH_CLIENT_INFO: &str = "client/watch";
+
+/// The API path to watch client information by a WebSocket, under the version.
+const PATH_TO_WATCH_CLIENT_INFO_WS: &str = "client/watch/ws";
+
+/// The API path to get the OpenAPI document, under the version.
+const PATH_TO_GET_OPENAPI: &str = "openapi.json";
+
+/// The endpoints listed in the discovery document. The OpenAPI document must describe
+/// each of them, which a test of `openapi` checks.
+const ENDPOINTS: &[(Endpoint, &str)] = &[
+    (Endpoint::ServerInfo, PATH_TO_GET_SERVER_INFO),
+    (Endpoint::GetClientInfo, PATH_TO_GET_CLIENT_INFO),
+    (Endpoint::PostClientInfo, PATH_TO_POST_CLIENT_INFO),
+    (Endpoint::DeregisterClientInfo, PATH_TO_DEREGISTER_CLIENT_INFO),
+    (Endpoint::Watch, PATH_TO_WATCH_CLIENT_INFO),
+    (Endpoint::WatchWebSocket, PATH_TO_WATCH_CLIENT_INFO_WS),
+    (Endpoint::OpenApi, PATH_TO_GET_OPENAPI),
+];
+
+/// The capabilities listed in the discovery document.
+const CAPABILITIES: &[Capability] = &[
+    Capability::Presence,
+    Capability::ObservedAddress,
+    Capability::Deregister,
+    Capability::LongPoll,
+    Capability::Streaming,
+];
+
+/// The header carrying the id of a request. Taken from the request if a proxy set it,
+/// and echoed in the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request id taken from a request.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
 
 /// The name of this App.
 const APP_NAME: &str = "Here";
@@ -21,21 +83,73 @@ const APP_NAME: &str = "Here";
 /// The version of this App.
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// The range of the client versions this server works with.
+const SUPPORTED_CLIENT_VERSIONS: &str = "^0.1";
+
+/// The newest client version warned about being newer than this server.
+static NEWEST_CLIENT_WARNED: Mutex<Option<String>> = Mutex::new(None);
+
 /// The default lifetime of the client information record. Seconds.
 pub(crate) const DEFAULT_LIFETIME: u64 = 60;
 
-/// The path where the database file put.
-pub(crate) const DATABASE_DUMPS_PATH: &str = "./client-info.db";
+/// The longest time a get request may wait for a change. Seconds.
+const MAX_WAIT_SECONDS: u64 = 300;
 
-/// The summary (entry) function of the server.
-pub(crate) async fn run_restful_api_server(addr: SocketAddr) -> Result<(), anyhow::Error> {
+/// The summary (entry) function of the server. When `shutdown` completes, stop accepting
+/// connections, and return once the requests in flight finish. With `serve_metrics`,
+/// the metrics are served here too, otherwise on the admin address.
+pub(crate) async fn run_restful_api_server(
+    addr: SocketAddr,
+    serve_metrics: bool,
+    events: Arc<EventHub>,
+    live: Arc<LiveConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), anyhow::Error> {
     /* Build an app by router. */
-    let app = Router::new()
-        .route(PATH_TO_GET_SERVER_INFO, get(get_server_info))
-        .route(PATH_TO_GET_CLIENT_INFO, get(get_client_info))
-        .route(PATH_TO_POST_CLIENT_INFO, post(post_client_info));
+    let mut app = Router::new().route(API_ROOT, get(get_discovery));
+    for prefix in [format!("{}/{}", API_ROOT, API_VERSION), API_ROOT.to_owned()] {
+        let path = |endpoint: &str| format!("{}/{}", prefix, endpoint);
+        app = app
+            .route(&path(PATH_TO_GET_SERVER_INFO), get(get_server_info))
+            .route(&path(PATH_TO_GET_CLIENT_INFO), get(get_client_info))
+            .route(&path(PATH_TO_POST_CLIENT_INFO), post(post_client_info))
+            .route(&path(PATH_TO_DEREGISTER_CLIENT_INFO), post(deregister_client_info))
+            .route(&path(PATH_TO_WATCH_CLIENT_INFO), get(watch_client_info_sse))
+            .route(&path(PATH_TO_WATCH_CLIENT_INFO_WS), get(watch_client_info_ws))
+            .route(&path(PATH_TO_GET_OPENAPI), get(get_openapi));
+    }
+    app = app
+        .route(PATH_TO_HEALTHZ, get(get_healthz))
+        .route(PATH_TO_READYZ, get(get_readyz));
+    if serve_metrics {
+        app = app.route(PATH_TO_METRICS, get(get_metrics));
+    }
+    let app = app
+        /* Inside the extensions, to read the live config. */
+        .layer(middleware::from_fn(limit_rate))
+        /* Before the rate limits, so a refused network takes no tokens. */
+        .layer(middleware::from_fn(check_access))
+        .layer(middleware::from_fn(limit_body))
+        /* Before the access rules and the rate limits, which take the address it resolves. */
+        .layer(middleware::from_fn(resolve_client_addr))
+        .layer(Extension(events))
+        .layer(Extension(live))
+        .layer(middleware::from_fn(check_client_version))
+        .layer(middleware::from_fn(plain_errors_to_problems))
+        .layer(middleware::from_fn(trace_request));
 
     /* Bind the address, and run the server. */
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+/// Serve only the metrics, on the admin address.
+pub(crate) async fn run_metrics_server(addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let app = Router::new().route(PATH_TO_METRICS, get(get_metrics));
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
         .await?;
@@ -43,133 +157,315 @@ pub(crate) async fn run_restful_api_server(addr: SocketAddr) -> Result<(), anyho
     Ok(())
 }
 
+/// The endpoint of a matched route, with or without the API version.
+pub(crate) fn endpoint_of_route(route: &str) -> Option<Endpoint> {
+    let path = route.strip_prefix(API_ROOT)?.strip_prefix('/')?;
+    let path = path.strip_prefix(API_VERSION).and_then(|p| p.strip_prefix('/')).unwrap_or(path);
+    ENDPOINTS.iter().find(|(_, p)| *p == path).map(|(endpoint, _)| *endpoint)
+}
+
+/// The paths the OpenAPI document describes: the discovery document, the endpoints
+/// of the API version, and the health checks and the metrics.
+#[cfg(test)]
+pub(crate) fn documented_paths() -> Vec<String> {
+    let mut paths = vec![API_ROOT.to_owned()];
+    paths.extend(ENDPOINTS.iter().map(|(_, path)| format!("{}/{}/{}", API_ROOT, API_VERSION, path)));
+    paths.extend([PATH_TO_HEALTHZ, PATH_TO_READYZ, PATH_TO_METRICS].map(str::to_owned));
+    paths
+}
+
+/// The get metrics method, in the Prometheus text format.
+#[utoipa::path(
+    get, path = "/metrics", tag = "operations",
+    responses((status = 200, description = "The metrics in the Prometheus text format. Only on `metrics_bind` if it is set.", content_type = "text/plain"))
+)]
+pub(crate) async fn get_metrics() -> impl IntoResponse {
+    /* Counting the records takes the storage lock. */
+    let text = tokio::task::spawn_blocking(|| METRICS.render()).await.expect("Rendering the metrics panicked.");
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+/// Run a request in a span carrying its id, log how it finished, and echo the id in the response.
+async fn trace_request<B>(req: Request<B>, next: Next<B>) -> Response {
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let route = req.extensions().get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+    let method = req.method().to_string();
+    let span = info_span!("request", id = %request_id, method = %req.method(), path = %req.uri().path());
+    /* The query may carry the passwords. */
+    let query = req.uri().query().map(redact_query).unwrap_or_default();
+    let started = Instant::now();
+    let context = RequestContext { id: request_id.clone(), path: req.uri().path().to_owned() };
+    async move {
+        debug!(%query, "Request started.");
+        let mut resp = problem::scope(context, next.run(req)).await;
+        let (status, elapsed) = (resp.status(), started.elapsed());
+        info!(status = status.as_u16(), elapsed_ms = elapsed.as_millis() as u64, "Request finished.");
+        METRICS.observe_request(&route, &method, status.as_u16(), elapsed);
+        if resp.extensions().get::<ResponseMessage>() == Some(&ResponseMessage::InvalidPassword) {
+            METRICS.observe_auth_failure(&route);
+        }
+        let value = HeaderValue::from_str(&request_id).expect("The request id is a valid header value.");
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+        resp
+    }.instrument(span).await
+}
+
+/// Refuse the clients of the versions this server does not support. The version is taken
+/// from the `X-Here-Client-Version` header, or the `User-Agent`. Requests without it,
+/// such as from scripts, are let through.
+async fn check_client_version<B>(req: Request<B>, next: Next<B>) -> Response {
+    let headers = req.headers();
+    let client_version = headers.get(CLIENT_VERSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| headers.get(USER_AGENT).and_then(|v| v.to_str().ok()).and_then(client_version_in_user_agent))
+        .map(str::to_owned);
+    if let Some(client_version) = client_version {
+        match version::compare(APP_VERSION, &client_version, SUPPORTED_CLIENT_VERSIONS) {
+            Ok(Compatibility::Compatible) => {},
+            Ok(Compatibility::Newer) => warn_newer_client(&client_version),
+            Ok(Compatibility::Incompatible) | Err(_) => {
+                info!(%client_version, "Refused a client of an unsupported version.");
+                let detail = format!(
+                    "The server {} supports the client versions {}, not {}.",
+                    APP_VERSION, SUPPORTED_CLIENT_VERSIONS, client_version
+                );
+                return ApiError::new(StatusCode::BAD_REQUEST, ResponseMessage::IncompatibleVersion, detail).into_response();
+            },
+        }
+    }
+    next.run(req).await
+}
+
+/// Warn that a client is newer than this server, once for each newer version seen.
+fn warn_newer_client(client_version: &str) {
+    let mut warned = NEWEST_CLIENT_WARNED.lock().expect("Warned client version lock poisoned.");
+    let is_newer = match warned.as_deref() {
+        Some(newest) => version::compare(newest, client_version, "*") == Ok(Compatibility::Newer),
+        None => true,
+    };
+    if is_newer {
+        warn!(%client_version, server_version = APP_VERSION, "A client is newer than this server. Consider upgrading the server.");
+        *warned = Some(client_version.to_owned());
+    }
+}
+
+/// Whether a request id from a request may be used: not too long, and only letters, digits, `-`, `_` or `.`.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// The discovery method: the API versions, their endpoints relative to the API root,
+/// and their capabilities.
+#[utoipa::path(
+    get, path = "/here", tag = "discovery",
+    responses((status = 200, description = "The API versions, the newest first.", body = Discovery))
+)]
+pub(crate) async fn get_discovery() -> impl IntoResponse {
+    Json(Discovery {
+        app: AppInfo::new(APP_NAME, APP_VERSION),
+        versions: vec![ApiVersion {
+            version: API_VERSION.to_owned(),
+            endpoints: ENDPOINTS.iter().map(|(e, path)| (*e, format!("{}/{}", API_VERSION, path))).collect(),
+            capabilities: CAPABILITIES.to_vec(),
+        }],
+    })
+}
+
+/// The get OpenAPI document method.
+#[utoipa::path(
+    get, path = "/here/v1/openapi.json", tag = "discovery",
+    responses((status = 200, description = "This document.", content_type = "application/json"))
+)]
+pub(crate) async fn get_openapi() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/json")], openapi::document())
+}
+
 /// The get server information method.
-async fn get_server_info() -> impl IntoResponse {
+#[utoipa::path(
+    get, path = "/here/v1/server", tag = "discovery",
+    responses(
+        (status = 200, description = "The server.", body = AppInfo),
+        (status = 400, description = "The request is invalid, or the client version is not supported.", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub(crate) async fn get_server_info() -> impl IntoResponse {
     /* Simply response an json of `AppInfo`. */
     (StatusCode::OK, Json(AppInfo::new(APP_NAME, APP_VERSION)))
 }
 
-/// The get client information method.
-async fn get_client_info(Query(params): Query<GetClientInfoParams>) -> impl IntoResponse {
-    /* Open (or create) the database. Response a server error when failed. */
-    let db = match Database::auto_from(
-            PathBuf::from(DATABASE_DUMPS_PATH), false
-    ) {
-        Ok(db) => db,
-        Err(_) => {
-            /* Build up a response with error message. */
-            let resp = GetClientInfoResponse::new(
-                None, &params.account, params.passwd
-            ).set_message(Some(ResponseMessage::DatabaseError));
-            /* Response a `500` status code. */
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp));
-        },
+/// The get client information method. With a `version` (or an `If-None-Match` header)
+/// equal to the current one, respond `304`; with a `wait` too, block until the record
+/// changes or expires, or the wait times out. The accounts without a password tell
+/// no version, so they are never waited for.
+#[utoipa::path(
+    get, path = "/here/v1/client/get", tag = "clients",
+    params(GetClientInfoParams, ("If-None-Match" = Option<String>, Header, description = "The same as `version`.")),
+    responses(
+        (status = 200, description = "The record of the account.", body = GetClientInfoResponse, headers(("ETag" = String, description = "The version of the record, unless the account has no password."))),
+        (status = 304, description = "The record is still at `version`, after the wait if any."),
+        (status = 400, description = "The request is invalid, or the client version is not supported.", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The password does not match the record, or the address is not allowed.", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No record of the account.", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Locked out after wrong passwords for the account or from the address.", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds until a password may be tried again."))),
+    )
+)]
+pub(crate) async fn get_client_info(
+    Query(params): Query<GetClientInfoParams>,
+    Extension(ClientAddr(client_addr)): Extension<ClientAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let known_version = params.version.clone().or_else(|| {
+        headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()).map(str::to_owned)
+    });
+    let wait = params.wait.unwrap_or(0).min(MAX_WAIT_SECONDS);
+    /* Subscribe before looking up, so a change in between is not missed. Only for waiting. */
+    let mut changes = match (&known_version, wait) {
+        (Some(_), 1..) => Some(CHANGES.subscribe(&params.account)),
+        _ => None,
     };
+    let deadline = Instant::now() + Duration::from_secs(wait);
+    loop {
+        /* Errors are responded at once. */
+        let (resp, version) = match query_client_info(&params, client_addr) {
+            Ok(found) => found,
+            Err(e) => return e.into_response(),
+        };
+        let version = match version {
+            Some(v) if known_version.as_ref() != Some(&v) => {
+                return (StatusCode::OK, [(ETAG, v)], Json(resp)).into_response();
+            },
+            Some(v) => v,
+            /* The data is hidden, so is its version, and no long poll tells its changes. */
+            None => return (StatusCode::OK, Json(resp)).into_response(),
+        };
+        let changes = match &mut changes {
+            Some(changes) => changes,
+            None => return (StatusCode::NOT_MODIFIED, [(ETAG, version)]).into_response(),
+        };
+        match timeout_at(deadline, changes.changed()).await {
+            /* Something changed, look up again. */
+            Ok(Ok(_)) => continue,
+            /* Timed out (or the notifier is gone), nothing changed. */
+            _ => return (StatusCode::NOT_MODIFIED, [(ETAG, version)]).into_response(),
+        }
+    }
+}
+
+/// Look up the client information of an account. Return the response,
+/// and the version of the record if the data is told. The password is not tried while
+/// the account or the address `ip` is locked out.
+fn query_client_info(params: &GetClientInfoParams, ip: IpAddr) -> Result<(GetClientInfoResponse, Option<String>), ApiError> {
     /* Query the item of record by account. */
-    let item = match db.query_item(
-        |r: &ClientInfoRecord| {
-            &r.client_info.account
+    let item = match find_record(&params.account) {
+        Some(i) => i,
+        None => {
+            /* Response a `404` status code. */
+            let detail = format!("No record of the account `{}`.", params.account);
+            return Err(ApiError::new(StatusCode::NOT_FOUND, ResponseMessage::NotFound, detail));
         },
-        params.account.clone()
-    ) {
-        Ok(i) => i,
-        Err(e) => {
-            match e {
-                DatabaseError::ItemNotFound => {
-                    /* Build up a response with error message. */
-                    let resp = GetClientInfoResponse::new(
-                        None, &params.account, params.passwd
-                    ).set_message(Some(ResponseMessage::NotFound));
-                    /* Response a `404` status code. */
-                    return (StatusCode::NOT_FOUND, Json(resp));
-                },
-                _ => {
-                    /* Build up a response with error message. */
-                    let resp = GetClientInfoResponse::new(
-                        None, &params.account, params.passwd
-                    ).set_message(Some(ResponseMessage::DatabaseError));
-                    /* Response a `500` status code. */
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp));
-                },
-            }
-        }
     };
-    
+
     let client_info = item.client_info.clone();
 
     let passwd_plaintext =  match &params.passwd {
         Some(p) => p,
         None => {
             if client_info.passwd.is_some() {
-                /* Build up a response with error message. */
-                let resp = GetClientInfoResponse::new(
-                    None, &params.account, params.passwd
-                ).set_message(Some(ResponseMessage::InvalidPassword));
                 /* Response a `403` status code. */
-                return (StatusCode::FORBIDDEN, Json(resp));
+                let detail = format!("The account `{}` needs a password.", params.account);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword, detail));
             }
             else {
+                /* Without a password, nobody can prove the account, so the addresses are not told.
+                Neither is the version, which could be matched against guessed addresses. */
                 let resp = GetClientInfoResponse::new(
                     Some(client_info.id), &client_info.account, None
                 ).set_ok(true);
-                return (StatusCode::OK, Json(resp));
+                return Ok((resp, None));
             }
         },
     };
 
+    if let Some(left) = lockout::locked_out(&params.account, ip) {
+        /* Response a `429` status code, without trying the password. */
+        let detail = format!(
+            "Too many wrong passwords for the account `{}` or from this address. Retry after {} second(s).",
+            params.account, left.as_secs_f64().ceil()
+        );
+        return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, ResponseMessage::LockedOut, detail).retry_after(left));
+    }
     if !client_info.verify_passwd(passwd_plaintext) {
-        /* Build up a response with error message. */
-        let resp = GetClientInfoResponse::new(
-          None, &params.account, None
-        ).set_message(Some(ResponseMessage::InvalidPassword));
+        lockout::fail(&params.account, ip);
         /* Response a `403` status code. */
-        (StatusCode::FORBIDDEN, Json(resp))
+        let detail = format!("The password does not match the account `{}`.", params.account);
+        Err(ApiError::new(StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword, detail))
     }
     else {
+        lockout::succeed(&params.account);
         let resp = GetClientInfoResponse::new(
             Some(client_info.id), &client_info.account, client_info.clone().passwd
-        ).set_ok(true).set_data(client_info);
-        (StatusCode::OK, Json(resp))
+        ).set_ok(true).set_observed(item.observed).set_presence(item.presence()).set_data(client_info);
+        Ok((resp, Some(record_version(Some(&item)))))
     }
 }
 
-/// The post client information method.
-async fn post_client_info(Json(client_info): Json<ClientInfo>) -> impl IntoResponse {
-    #[cfg(feature = "debug-printing")] println!("A new post request from client, id = {}.", client_info.id);
-
-    let client_lifetime = DEFAULT_LIFETIME;
-    /* Open (or create) the database. Response a server error when failed. */
-    let mut db = match Database::auto_from(
-            PathBuf::from(DATABASE_DUMPS_PATH), false
-    ) {
-        Ok(db) => db,
-        Err(_) => {
-            /* Build up a response with error message. */
-            let resp = PostClientInfoResponse::new(
-                client_info.id, &client_info.account, client_info.passwd
-            ).set_message(Some(ResponseMessage::DatabaseError));
-            /* Response a `500` status code. */
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp));
-        },
-    };
-    /* Add the record to the database. Response a server error when failed. */
-    if let Err(_) = db.add_item(ClientInfoRecord::new(client_info.clone(), client_lifetime)) {
-        /* Build up a response with error message. */
-        let resp = PostClientInfoResponse::new(
-            client_info.id, &client_info.account, client_info.passwd
-        ).set_message(Some(ResponseMessage::DatabaseError));
-        /* Response a `500` status code. */
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp));
+/// The post client information method. The client information is validated first,
+/// and every invalid field is responded.
+#[utoipa::path(
+    post, path = "/here/v1/client/post", tag = "clients",
+    request_body = ClientInfo,
+    responses(
+        (status = 200, description = "Recorded, for `lifetime` seconds.", body = PostClientInfoResponse),
+        (status = 400, description = "The request is invalid, or the client version is not supported.", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The address is not allowed to post, or not for the account.", body = Problem, content_type = "application/problem+json"),
+        (status = 413, description = "The body is too large.", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The client information is invalid. `errors` tells the fields.", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Over a rate limit of the account or the address.", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds until the next request is taken."))),
+        (status = 500, description = "The record cannot be written.", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub(crate) async fn post_client_info(
+    Extension(events): Extension<Arc<EventHub>>,
+    Extension(live): Extension<Arc<LiveConfig>>,
+    Extension(ClientAddr(client_addr)): Extension<ClientAddr>,
+    Json(client_info): Json<ClientInfo>,
+) -> Result<Json<PostClientInfoResponse>, ApiError> {
+    debug!(id = client_info.id, account = %client_info.account, "A new post request from client.");
+
+    let config = live.current();
+    let problems = check_client_info(&client_info, &config.addresses);
+    if !problems.is_empty() {
+        /* Response a `422` status code. */
+        let detail = format!("The client information of the account `{}` is invalid.", client_info.account);
+        return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, ResponseMessage::InvalidRequest, detail).with_errors(problems));
     }
-    /* Dump the data to the database, and then the data will be storage. */
-    if let Err(_) = db.dump_db() {
-        /* Build up a response with error message. */
-        let resp = PostClientInfoResponse::new(
-            client_info.id, &client_info.account, client_info.passwd
-        ).set_message(Some(ResponseMessage::DatabaseError));
+    let client_lifetime = config.lifetime;
+    /* Replace the record of the account in the database. Response a server error when failed. */
+    let record = ClientInfoRecord::new(client_info.clone(), client_lifetime)
+        .set_observed(Some(client_addr));
+    /* Syncing the WAL blocks, so it is done off the async workers. */
+    let written = tokio::task::spawn_blocking({
+        let record = record.clone();
+        move || replace_record(record)
+    }).await.expect("Record writing task panicked.");
+    let former = written.map_err(|e| {
         /* Response a `500` status code. */
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp));
-    }
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, ResponseMessage::DatabaseError, format!("Cannot write the record: {}", e))
+    })?;
+    /* Tell the others if the account came online or moved, or just renewed. */
+    let kind = if former.map(|f| f.offline || addresses_differ(&f, &record)).unwrap_or(true) {
+        EventKind::Changed
+    } else {
+        EventKind::Renewed
+    };
+    events.publish(ClientEvent::new(kind, &record));
 
     /* If success after those steps, send a ok response with a lifetime. */
     let resp = PostClientInfoResponse::new(
@@ -178,5 +474,56 @@ async fn post_client_info(Json(client_info): Json<ClientInfo>) -> impl IntoRespo
         .set_ok(true)
         .set_lifetime(client_lifetime);
     /* Response a `200` status code. */
-    (StatusCode::OK, Json(resp))
-}
\ No newline at end of file
+    Ok(Json(resp))
+}
+
+/// The deregister client information method. The record goes offline at once,
+/// if the password hash matches it. An account without a password cannot be deregistered.
+#[utoipa::path(
+    post, path = "/here/v1/client/deregister", tag = "clients",
+    request_body = ClientInfo,
+    responses(
+        (status = 200, description = "The record is offline.", body = PostClientInfoResponse),
+        (status = 400, description = "The request is invalid, or the client version is not supported.", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The password hash does not match the record, or the account has no password.", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No online record of the account.", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "The record cannot be written.", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+pub(crate) async fn deregister_client_info(
+    Extension(events): Extension<Arc<EventHub>>,
+    Json(client_info): Json<ClientInfo>,
+) -> Result<Json<PostClientInfoResponse>, ApiError> {
+    let resp = PostClientInfoResponse::new(client_info.id, &client_info.account, client_info.passwd.clone());
+    let deregistered = tokio::task::spawn_blocking({
+        let client_info = client_info.clone();
+        move || deregister_record(&client_info)
+    }).await.expect("Record writing task panicked.");
+    match deregistered {
+        Ok(Deregistered::Done(record)) => {
+            events.publish(ClientEvent::new(EventKind::Expired, &record));
+            Ok(Json(resp.set_ok(true)))
+        },
+        Ok(Deregistered::NotFound) => Err(ApiError::new(
+            StatusCode::NOT_FOUND, ResponseMessage::NotFound, format!("No online record of the account `{}`.", client_info.account)
+        )),
+        Ok(Deregistered::InvalidPassword) => Err(ApiError::new(
+            StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword, format!("The password does not match the account `{}`.", client_info.account)
+        )),
+        Ok(Deregistered::NoPassword) => Err(ApiError::new(
+            StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword,
+            format!("The account `{}` has no password, so it cannot be deregistered. Let it expire.", client_info.account)
+        )),
+        Err(e) => Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR, ResponseMessage::DatabaseError, format!("Cannot write the record: {}", e)
+        )),
+    }
+}
+
+#[test]
+fn test_endpoint_of_route() {
+    assert_eq!(endpoint_of_route("/here/v1/client/post"), Some(Endpoint::PostClientInfo));
+    assert_eq!(endpoint_of_route("/here/client/watch/ws"), Some(Endpoint::WatchWebSocket));
+    assert_eq!(endpoint_of_route("/here"), None);
+    assert_eq!(endpoint_of_route("/healthz"), None);
+}
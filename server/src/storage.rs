@@ -1,14 +1,34 @@
-use std::{path::PathBuf, time::Duration};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex, MutexGuard, OnceLock, RwLock, RwLockWriteGuard};
+use std::{io, net::IpAddr, path::Path, time::Duration};
 
 use chrono::{DateTime, Utc, serde::ts_seconds};
-use tinydb::{Database, error::DatabaseError};
+use tokio::sync::watch;
+use tracing::{debug, error};
 use utils::client::ClientInfo;
+use utils::server::{Presence, PresenceState};
 
 use serde_derive::{Serialize, Deserialize};
 
-use crate::restful::DATABASE_DUMPS_PATH;
+use crate::persist::Store;
 
-#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Debug)]
+/// The snapshot of the records.
+const SNAPSHOT_PATH: &str = "./client-info.snapshot.json";
+
+/// The write-ahead log of the records after the snapshot.
+const WAL_PATH: &str = "./client-info.wal";
+
+/// The database file of the former versions. Its records are taken into the first snapshot.
+const LEGACY_DATABASE_PATH: &str = "./client-info.db";
+
+/// How long a record stays stale after its lifetime, before it goes offline. Seconds.
+pub(crate) const STALE_GRACE: u64 = 60;
+
+/// How long an offline record is kept for its last seen time, before it is removed. Seconds.
+pub(crate) const OFFLINE_RETENTION: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Debug)]
 pub(crate) struct ClientInfoRecord {
     pub(crate) client_info: ClientInfo,
 
@@ -16,6 +36,14 @@ pub(crate) struct ClientInfoRecord {
     record_time: DateTime<Utc>,
 
     lifetime: u64,
+
+    /// The source address of the post request which made this record.
+    #[serde(default)]
+    pub(crate) observed: Option<IpAddr>,
+
+    /// Whether the record has gone offline, and it has been told.
+    #[serde(default)]
+    pub(crate) offline: bool,
 }
 
 impl ClientInfoRecord {
@@ -23,50 +51,353 @@ impl ClientInfoRecord {
     pub(crate) fn new(client_info: ClientInfo, lifetime: u64) -> Self {
         Self {
             client_info,
-            record_time: DateTime::from(Utc::now()),
+            record_time: Utc::now(),
             lifetime,
+            observed: None,
+            offline: false,
         }
     }
+
+    /// Set the record time, such as of a record from the former versions.
+    pub(crate) fn set_record_time(mut self, record_time: DateTime<Utc>) -> Self {
+        self.record_time = record_time;
+        self
+    }
+
+    /// Set the source address which the server observed.
+    pub(crate) fn set_observed(mut self, observed: Option<IpAddr>) -> Self {
+        self.observed = observed;
+        self
+    }
+
+    /// The presence of the client, as of now.
+    pub(crate) fn presence(&self) -> Presence {
+        let state = if self.offline || is_outdated(self, self.lifetime + STALE_GRACE) {
+            PresenceState::Offline
+        }
+        else if is_outdated(self, self.lifetime) {
+            PresenceState::Stale
+        }
+        else {
+            PresenceState::Online
+        };
+        Presence {
+            state,
+            record_time: self.record_time.timestamp(),
+            expiry: self.record_time.timestamp() + self.lifetime as i64,
+            last_seen: self.record_time.timestamp(),
+        }
+    }
+}
+
+/// The records of the accounts. Set by `open`.
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+
+/// A copy of the records for the readers, updated by the writers after the store, while they
+/// still hold it. So the readers never wait for the WAL being synced.
+static RECORDS: LazyLock<RwLock<HashMap<String, ClientInfoRecord>>> = LazyLock::new(Default::default);
+
+/// Wakes up who is waiting for the record of an account to change.
+pub(crate) static CHANGES: LazyLock<ChangeNotifier> = LazyLock::new(ChangeNotifier::default);
+
+/// A change counter for each account being waited on.
+#[derive(Default)]
+pub(crate) struct ChangeNotifier {
+    senders: Mutex<HashMap<String, watch::Sender<u64>>>,
+    closed: AtomicBool,
+}
+
+impl ChangeNotifier {
+    /// Get a subscription which is marked changed when the record of `account` changes or expires.
+    /// Its `changed()` fails at once if the notifier is closed.
+    pub(crate) fn subscribe(&self, account: &str) -> Changes<'_> {
+        let mut senders = self.senders.lock().expect("Change notifier lock poisoned.");
+        let receiver = if self.closed.load(Ordering::SeqCst) {
+            watch::channel(0).1
+        }
+        else {
+            senders.entry(account.to_owned())
+                .or_insert_with(|| watch::channel(0).0)
+                .subscribe()
+        };
+        Changes { notifier: self, account: account.to_owned(), receiver }
+    }
+
+    /// Wake up the receivers of `account`.
+    pub(crate) fn notify(&self, account: &str) {
+        let mut senders = self.senders.lock().expect("Change notifier lock poisoned.");
+        if let Some(sender) = senders.get(account) {
+            if sender.receiver_count() == 0 {
+                /* Nobody is waiting any more. */
+                senders.remove(account);
+            }
+            else {
+                sender.send_modify(|counter| *counter += 1);
+            }
+        }
+    }
+
+    /// Stop every waiting, for shutting down.
+    pub(crate) fn close(&self) {
+        let mut senders = self.senders.lock().expect("Change notifier lock poisoned.");
+        self.closed.store(true, Ordering::SeqCst);
+        /* Dropping the senders fails the `changed()` of the receivers. */
+        senders.clear();
+    }
+}
+
+/// The changes of the record of an account, from `ChangeNotifier::subscribe`. The entry
+/// of the account is removed when its last subscription is dropped, so the accounts
+/// queried once are not kept.
+pub(crate) struct Changes<'a> {
+    notifier: &'a ChangeNotifier,
+    account: String,
+    receiver: watch::Receiver<u64>,
+}
+
+impl Changes<'_> {
+    /// Wait for a change. Fails if the notifier is closed.
+    pub(crate) async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        self.receiver.changed().await
+    }
+}
+
+impl Drop for Changes<'_> {
+    fn drop(&mut self) {
+        let mut senders = self.notifier.senders.lock().unwrap_or_else(|e| e.into_inner());
+        /* This receiver is still counted. */
+        if senders.get(&self.account).is_some_and(|s| s.receiver_count() <= 1) {
+            senders.remove(&self.account);
+        }
+    }
+}
+
+/// The version of the record of an account, used as the ETag. It only changes when
+/// the content changes, so a renewal with the same addresses keeps it. SHA-256 is used,
+/// since it stays the same across the builds and the restarts.
+pub(crate) fn record_version(record: Option<&ClientInfoRecord>) -> String {
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    let content = record.map(|r| (&r.client_info.ipv4s, &r.client_info.ipv6s, &r.observed, &r.client_info.passwd, r.offline));
+    let mut hasher = Sha256::new();
+    hasher.input(&serde_json::to_vec(&content).expect("Cannot serialize the record content."));
+    format!("\"{}\"", &hasher.result_str()[..16])
+}
+
+/// Recover the records from the files. Return how many there are.
+pub(crate) fn open() -> io::Result<usize> {
+    let store = Store::open(Path::new(SNAPSHOT_PATH), Path::new(WAL_PATH), Path::new(LEGACY_DATABASE_PATH))?;
+    let count = store.records().count();
+    *records() = store.records().map(|r| (r.client_info.account.clone(), r.clone())).collect();
+    if STORE.set(Mutex::new(store)).is_err() {
+        panic!("The storage is opened twice.");
+    }
+    Ok(count)
+}
+
+fn store() -> MutexGuard<'static, Store> {
+    STORE.get().expect("The storage is not opened.").lock().expect("Storage lock poisoned.")
+}
+
+/// The copy of the records, for updating it.
+fn records() -> RwLockWriteGuard<'static, HashMap<String, ClientInfoRecord>> {
+    RECORDS.write().expect("Records copy lock poisoned.")
+}
+
+/// The record of an account, if any. Does not wait for the writing in progress.
+pub(crate) fn find_record(account: &str) -> Option<ClientInfoRecord> {
+    RECORDS.read().expect("Records copy lock poisoned.").get(account).cloned()
+}
+
+/// The presence states of all the records, as of now. Does not wait for the writing in progress.
+pub(crate) fn count_presence() -> Vec<PresenceState> {
+    RECORDS.read().expect("Records copy lock poisoned.").values().map(|r| r.presence().state).collect()
 }
 
-/// Clean a outdated client information record.
-pub(crate) fn clean_outdated(lifetime: u64) -> Result<(), DatabaseError> {
-    /* Open (or create) a database. */
-    let db = Database::auto_from(
-        PathBuf::from(DATABASE_DUMPS_PATH),
-        false
-    )?;
-    /* Query the item with `lifetime` param. Record with same lifetime will be select. */
-    let item = db.query_item(
-        |s: &ClientInfoRecord| {
-            &s.lifetime
-        },
-        lifetime
-    )?;
-    /* Compare the item's lifetime with the param lifetime. */
-    if is_outdated(item, lifetime) {
-        #[cfg(feature = "debug-printing")] println!("Found an outdated storage: {:?}", item);
-        /* Open (or create) the database. */
-        let mut db = Database::auto_from(
-            PathBuf::from(DATABASE_DUMPS_PATH),
-            false
-        )?;
+/// Whether the storage is opened and writable. Then whether the latest write of the
+/// records succeeded. Waits for the writing in progress, so it is called by `spawn_blocking`.
+pub(crate) fn check_health() -> (Result<(), String>, Result<(), String>) {
+    let mut store = match STORE.get().map(|s| s.lock()) {
+        Some(Ok(store)) => store,
+        Some(Err(_)) => return (Err("The storage lock is poisoned.".to_owned()), Err("Unknown.".to_owned())),
+        None => return (Err("The storage is not opened.".to_owned()), Err("Unknown.".to_owned())),
+    };
+    let writable = store.probe_writable().map_err(|e| format!("The storage is not writable: {}", e));
+    let persisted = match store.last_failure() {
+        Some(failure) => Err(failure.to_owned()),
+        None => Ok(()),
+    };
+    (writable, persisted)
+}
+
+/// Put the record of an account, replacing the former one. Return the former record, if any.
+/// Blocks until the WAL is synced, so the async handlers call it by `spawn_blocking`.
+pub(crate) fn replace_record(record: ClientInfoRecord) -> io::Result<Option<ClientInfoRecord>> {
+    let mut store = store();
+    let former = store.put(record.clone())?;
+    records().insert(record.client_info.account.clone(), record.clone());
+    drop(store);
+    if record_version(former.as_ref()) != record_version(Some(&record)) {
+        CHANGES.notify(&record.client_info.account);
+    }
+    Ok(former)
+}
+
+/// Mark the records beyond the grace window offline, and remove the offline records
+/// beyond the retention. Return the records which just went offline.
+pub(crate) fn clean_outdated() -> io::Result<Vec<ClientInfoRecord>> {
+    let mut store = store();
+    /* Compare each item's record time with its lifetime. */
+    let newly_offline: Vec<ClientInfoRecord> = store.records()
+        .filter(|r| !r.offline && is_outdated(r, r.lifetime + STALE_GRACE))
+        .cloned()
+        .collect();
+    let expired: Vec<ClientInfoRecord> = store.records()
+        .filter(|r| is_outdated(r, r.lifetime + STALE_GRACE + OFFLINE_RETENTION))
+        .cloned()
+        .collect();
+    for item in &expired {
+        debug!(account = %item.client_info.account, "Found an outdated storage.");
         /* Remove a outdated item. */
-        db.remove_item(item)?;
-        /* This step is important. It makes changes write down to the file(s). */
-        db.dump_db()?;
+        store.remove(&item.client_info.account)?;
+        records().remove(&item.client_info.account);
     }
-    Ok(())
+    for item in newly_offline.iter().filter(|r| !expired.contains(r)) {
+        debug!(account = %item.client_info.account, "Found an offline storage.");
+        /* Keep the record for the last seen time. */
+        let offline = ClientInfoRecord { offline: true, ..item.clone() };
+        store.put(offline.clone())?;
+        records().insert(offline.client_info.account.clone(), offline);
+    }
+    for item in newly_offline.iter().chain(&expired) {
+        CHANGES.notify(&item.client_info.account);
+    }
+    Ok(newly_offline)
+}
+
+/// What deregistering an account did.
+pub(crate) enum Deregistered {
+    /// The record is offline now.
+    Done(ClientInfoRecord),
+    NotFound,
+    InvalidPassword,
+    /// The account has no password, so nobody can prove it.
+    NoPassword,
+}
+
+/// Mark the record of an account offline at once, if the password hash matches it.
+/// Blocks until the WAL is synced, as `replace_record`.
+pub(crate) fn deregister_record(client_info: &ClientInfo) -> io::Result<Deregistered> {
+    let mut store = store();
+    let record = match store.get(&client_info.account) {
+        Some(r) if !r.offline => r.clone(),
+        _ => return Ok(Deregistered::NotFound),
+    };
+    if let Some(refused) = refuse_deregistering(&record, client_info) {
+        return Ok(refused);
+    }
+    let offline = ClientInfoRecord { offline: true, ..record };
+    store.put(offline.clone())?;
+    records().insert(offline.client_info.account.clone(), offline.clone());
+    CHANGES.notify(&client_info.account);
+    Ok(Deregistered::Done(offline))
+}
+
+/// Why `client_info` may not take `record` offline, if it may not. The password hash is the same proof
+/// as posting, which could replace the record anyway. A record without a password is
+/// refused, or anyone could take it offline.
+fn refuse_deregistering(record: &ClientInfoRecord, client_info: &ClientInfo) -> Option<Deregistered> {
+    match (&record.client_info.passwd, &client_info.passwd) {
+        (None, _) => Some(Deregistered::NoPassword),
+        (Some(hash), Some(given)) if hash == given => None,
+        _ => Some(Deregistered::InvalidPassword),
+    }
+}
+
+/// Wait for the writing in progress, write a snapshot, and block the others until the guard
+/// is dropped, so the process exits with the files whole.
+pub(crate) fn close() -> MutexGuard<'static, Store> {
+    let mut store = STORE.get().expect("The storage is not opened.").lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = store.snapshot() {
+        error!("Cannot write the snapshot, the WAL still has the records: {}", e);
+    }
+    store
+}
+
+/// Whether two records of an account report different addresses.
+pub(crate) fn addresses_differ(a: &ClientInfoRecord, b: &ClientInfoRecord) -> bool {
+    a.client_info.ipv4s != b.client_info.ipv4s
+        || a.client_info.ipv6s != b.client_info.ipv6s
+        || a.observed != b.observed
 }
 
 /// A simple function compare the lifetime of the record and `lifetime` param,
 /// and return if the different between them is bigger then the `lifetime` param.
 fn is_outdated(s: &ClientInfoRecord, lifetime: u64) -> bool {
     /* Get the different. */
-    let diff = DateTime::from(Utc::now()) - s.record_time.to_owned();
-    /* Cover the value `diff` to a `Duration` in `std`. */
-    let diff_std = diff.to_std()
-        .expect("Cannot cover the duration from `chrono` to `std` implementation.");
-    /* Compare. Return `true` if bigger, or `false` otherwise. */
-    diff_std > Duration::from_secs(lifetime)
-}
\ No newline at end of file
+    let diff = Utc::now() - s.record_time.to_owned();
+    /* Cover the value `diff` to a `Duration` in `std`. A record from the future is not outdated. */
+    match diff.to_std() {
+        /* Compare. Return `true` if bigger, or `false` otherwise. */
+        Ok(diff_std) => diff_std > Duration::from_secs(lifetime),
+        Err(_) => false,
+    }
+}
+
+#[tokio::test]
+async fn test_change_notifier() {
+    let notifier = ChangeNotifier::default();
+    let mut changes = notifier.subscribe("umoho");
+    /* Other accounts do not wake it up. */
+    notifier.notify("nas");
+    assert!(!changes.receiver.has_changed().unwrap());
+    notifier.notify("umoho");
+    assert!(changes.receiver.has_changed().unwrap());
+    changes.changed().await.unwrap();
+    /* The entry is dropped with the last subscription of the account. */
+    let other = notifier.subscribe("umoho");
+    drop(changes);
+    assert_eq!(notifier.senders.lock().unwrap().len(), 1);
+    drop(other);
+    assert!(notifier.senders.lock().unwrap().is_empty());
+    /* Closing fails the waiting at once, and the later ones too. */
+    let mut receiver = notifier.subscribe("umoho");
+    notifier.close();
+    assert!(receiver.changed().await.is_err());
+    assert!(notifier.subscribe("nas").changed().await.is_err());
+}
+
+#[test]
+fn test_record_version() {
+    let record = ClientInfoRecord::new(ClientInfo::new(1, "umoho"), 60);
+    /* Stable across the builds, so the versions the consumers hold stay valid. */
+    assert_eq!(record_version(None), "\"74234e98afe7498f\"");
+    let renewed = ClientInfoRecord::new(ClientInfo::new(2, "umoho"), 120);
+    assert_eq!(record_version(Some(&record)), record_version(Some(&renewed)));
+    assert_ne!(record_version(Some(&record)), record_version(Some(&record.clone().set_observed(Some([192, 0, 2, 1].into())))));
+}
+
+#[test]
+fn test_presence_states() {
+    let mut record = ClientInfoRecord::new(ClientInfo::new(1, "umoho"), 60);
+    assert_eq!(record.presence().state, PresenceState::Online);
+    record.record_time = Utc::now() - chrono::Duration::seconds(90);
+    assert_eq!(record.presence().state, PresenceState::Stale);
+    record.record_time = Utc::now() - chrono::Duration::seconds(60 + STALE_GRACE as i64 + 1);
+    let presence = record.presence();
+    assert_eq!(presence.state, PresenceState::Offline);
+    assert_eq!(presence.last_seen, record.record_time.timestamp());
+    assert_eq!(presence.expiry, presence.record_time + 60);
+}
+
+#[test]
+fn test_refuse_deregistering() {
+    let client_info = ClientInfo::builder(1, "umoho", &Some("password".to_owned()));
+    let record = ClientInfoRecord::new(client_info.clone(), 60);
+    assert!(refuse_deregistering(&record, &client_info).is_none());
+    assert!(matches!(refuse_deregistering(&record, &ClientInfo::new(1, "umoho")), Some(Deregistered::InvalidPassword)));
+    /* Nobody proves an account without a password, not even without one. */
+    let passwordless = ClientInfoRecord::new(ClientInfo::new(1, "nas"), 60);
+    assert!(matches!(refuse_deregistering(&passwordless, &ClientInfo::new(1, "nas")), Some(Deregistered::NoPassword)));
+    assert!(matches!(refuse_deregistering(&passwordless, &client_info), Some(Deregistered::NoPassword)));
+}
@@ -0,0 +1,147 @@
+use std::net::IpAddr;
+
+use axum::{body::Body, http::{Method, Request, StatusCode, header::CONTENT_LENGTH}, middleware::Next, response::{IntoResponse, Response}};
+use hyper::body::HttpBody;
+use serde_derive::{Serialize, Deserialize};
+use utils::client::{ClientInfo, MAX_ACCOUNT_LENGTH, is_valid_account};
+use utils::config::{self, FieldError};
+use utils::server::{FieldProblem, ResponseMessage};
+
+use crate::problem::ApiError;
+
+/// The largest body of a request. Bytes. A post with the most addresses is far smaller.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// The bounds of the addresses of each family in the config.
+const MAX_PER_FAMILY_RANGE: std::ops::RangeInclusive<u64> = 1..=256;
+
+/// The length of a SHA-256 hash in hex, which is what the clients send as the password.
+const PASSWD_HASH_LENGTH: usize = 64;
+
+/// Which addresses the posts may carry, in the server config.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct AddressPolicy {
+    /// The most addresses of each family in a post.
+    pub(crate) max_per_family: u64,
+    /// Take `127.0.0.0/8` and `::1`, such as for testing on one machine.
+    pub(crate) allow_loopback: bool,
+    pub(crate) allow_multicast: bool,
+    /// Take `0.0.0.0` and `::`.
+    pub(crate) allow_unspecified: bool,
+}
+
+impl Default for AddressPolicy {
+    fn default() -> Self {
+        Self { max_per_family: 16, allow_loopback: false, allow_multicast: false, allow_unspecified: false }
+    }
+}
+
+impl AddressPolicy {
+    pub(crate) fn validate(&self, errors: &mut Vec<FieldError>) {
+        errors.extend(config::check_range("addresses.max_per_family", self.max_per_family, MAX_PER_FAMILY_RANGE));
+    }
+
+    /// Why an address is refused, if it is.
+    fn refuse(&self, ip: IpAddr) -> Option<&'static str> {
+        /* An IPv4-mapped IPv6 address is judged as the IPv4 one. */
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        if ip.is_unspecified() && !self.allow_unspecified {
+            Some("is unspecified")
+        }
+        else if ip.is_loopback() && !self.allow_loopback {
+            Some("is a loopback address")
+        }
+        else if ip.is_multicast() && !self.allow_multicast {
+            Some("is a multicast address")
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// Check a posted client information: the account, the password hash, and the addresses
+/// by `policy`. Every invalid field is reported.
+pub(crate) fn check_client_info(info: &ClientInfo, policy: &AddressPolicy) -> Vec<FieldProblem> {
+    let mut problems = vec![];
+    if !is_valid_account(&info.account) {
+        problems.push(FieldProblem::new(
+            "account", format!("use 1 to {} letters, digits, `-`, `_`, `.` or `@`", MAX_ACCOUNT_LENGTH)
+        ));
+    }
+    if let Some(passwd) = &info.passwd {
+        if passwd.len() != PASSWD_HASH_LENGTH || !passwd.chars().all(|c| c.is_ascii_hexdigit()) {
+            problems.push(FieldProblem::new("passwd", "is not a SHA-256 hash in hex"));
+        }
+    }
+    check_addresses("ipv4s", info.ipv4s.iter().map(|ip| IpAddr::V4(*ip)), info.ipv4s.len(), policy, &mut problems);
+    check_addresses("ipv6s", info.ipv6s.iter().map(|ip| IpAddr::V6(*ip)), info.ipv6s.len(), policy, &mut problems);
+    problems
+}
+
+/// Check the addresses of one family. Too many of them are not checked one by one.
+fn check_addresses(field: &str, ips: impl Iterator<Item = IpAddr>, count: usize, policy: &AddressPolicy, problems: &mut Vec<FieldProblem>) {
+    if count as u64 > policy.max_per_family {
+        problems.push(FieldProblem::new(field, format!("has {} addresses, at most {} are taken", count, policy.max_per_family)));
+        return;
+    }
+    for (i, ip) in ips.enumerate() {
+        if let Some(reason) = policy.refuse(ip) {
+            problems.push(FieldProblem::new(format!("{}[{}]", field, i), format!("{} {}", ip, reason)));
+        }
+    }
+}
+
+/// Refuse the bodies larger than `MAX_BODY_BYTES` with `413`, before anything reads them.
+/// A body without a length is read here, up to the limit.
+pub(crate) async fn limit_body(req: Request<Body>, next: Next<Body>) -> Response {
+    let length = req.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    match length {
+        Some(length) if length > MAX_BODY_BYTES => return body_too_large(),
+        /* The server reads no more than the length. */
+        Some(_) => return next.run(req).await,
+        None if req.method() != Method::POST => return next.run(req).await,
+        None => {},
+    }
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, ResponseMessage::InvalidRequest, format!("Cannot read the body: {}", e)).into_response(),
+        };
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return body_too_large();
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+fn body_too_large() -> Response {
+    let detail = format!("The body is larger than {} bytes.", MAX_BODY_BYTES);
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, ResponseMessage::InvalidRequest, detail).into_response()
+}
+
+#[test]
+fn test_check_client_info() {
+    let policy = AddressPolicy::default();
+    let mut info = ClientInfo::builder(1, "umoho", &Some("password".to_owned()));
+    info.ipv4s = vec!["192.0.2.1".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+    info.ipv6s = vec!["::ffff:0.0.0.0".parse().unwrap(), "ff02::1".parse().unwrap()];
+    let fields: Vec<String> = check_client_info(&info, &policy).into_iter().map(|p| p.field).collect();
+    assert_eq!(fields, ["ipv4s[1]", "ipv6s[0]", "ipv6s[1]"]);
+    let allowing = AddressPolicy { allow_loopback: true, allow_multicast: true, allow_unspecified: true, ..policy.clone() };
+    assert!(check_client_info(&info, &allowing).is_empty());
+
+    info.account = "a b".to_owned();
+    info.passwd = Some("password".to_owned());
+    info.ipv4s = vec!["192.0.2.1".parse().unwrap(); 17];
+    info.ipv6s = vec![];
+    let fields: Vec<String> = check_client_info(&info, &policy).into_iter().map(|p| p.field).collect();
+    assert_eq!(fields, ["account", "passwd", "ipv4s"]);
+}
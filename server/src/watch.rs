@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::{Extension, extract::{Query, ws::{Message, WebSocket, WebSocketUpgrade}}, http::{HeaderMap, StatusCode}, response::{IntoResponse, sse::{Event, KeepAlive, Sse}}};
+use utils::server::{Problem, ResponseMessage, WatchParams};
+
+use crate::events::{EventHub, EventId, Received, Subscription};
+use crate::lockout;
+use crate::problem::ApiError;
+use crate::proxy::ClientAddr;
+use crate::storage::find_record;
+
+/// The header an SSE consumer sends its last received event id in, when it reconnects.
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+/// The name of the notice sent when some events are missed.
+const MISSED_EVENT_NAME: &str = "missed";
+
+/// The events of some accounts which a consumer may see.
+struct Watch {
+    accounts: HashSet<String>,
+    passwd: String,
+    /// The consumer, whose wrong passwords are counted.
+    ip: IpAddr,
+    subscription: Subscription,
+}
+
+impl Watch {
+    /// Subscribe to the hub, resuming from the cursor in the params or the `Last-Event-ID` header.
+    /// A consumer without a password is refused, since it could see no event. The password is
+    /// tried as by a query: not while locked out, and a wrong one is counted.
+    fn new(events: &EventHub, params: WatchParams, headers: &HeaderMap, ip: IpAddr) -> Result<Self, ApiError> {
+        let mut accounts: HashSet<String> = params.accounts().into_iter().map(str::to_owned).collect();
+        let passwd = match params.passwd {
+            Some(passwd) => passwd,
+            None => {
+                /* Response a `403` status code. */
+                let detail = format!("Watching `{}` needs the password of the accounts.", params.accounts);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword, detail));
+            },
+        };
+        if let Some(left) = accounts.iter().filter_map(|account| lockout::locked_out(account, ip)).max() {
+            /* Response a `429` status code, without trying the password. */
+            let detail = format!(
+                "Too many wrong passwords for the accounts `{}` or from this address. Retry after {} second(s).",
+                params.accounts, left.as_secs_f64().ceil()
+            );
+            return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, ResponseMessage::LockedOut, detail).retry_after(left));
+        }
+        /* Try the password on the recorded accounts now. The others are tried by their first events. */
+        let mut mismatched = false;
+        accounts.retain(|account| match find_record(account) {
+            Some(record) if record.client_info.passwd.is_some() => {
+                if record.client_info.verify_passwd(&passwd) {
+                    lockout::succeed(account);
+                    true
+                }
+                else {
+                    lockout::fail(account, ip);
+                    mismatched = true;
+                    false
+                }
+            },
+            /* Not recorded yet, or without a password, whose events nobody sees. */
+            _ => true,
+        });
+        if mismatched && accounts.is_empty() {
+            /* Response a `403` status code. */
+            let detail = format!("The password does not match the accounts `{}`.", params.accounts);
+            return Err(ApiError::new(StatusCode::FORBIDDEN, ResponseMessage::InvalidPassword, detail));
+        }
+        let cursor = params.cursor.or_else(|| {
+            headers.get(LAST_EVENT_ID_HEADER).and_then(|v| v.to_str().ok()).map(str::to_owned)
+        });
+        /* A garbled cursor cannot be resumed from, the same as one from before a restart. */
+        let cursor = cursor.map(|c| c.parse().unwrap_or(EventId { epoch: 0, seq: u64::MAX }));
+        Ok(Self { accounts, passwd, ip, subscription: events.subscribe(cursor) })
+    }
+
+    /// Wait for the next event of the watched accounts. An account whose password turns out
+    /// not to match is counted as a wrong password, and not watched any more.
+    async fn next(&mut self) -> Option<Received> {
+        loop {
+            match self.subscription.recv().await? {
+                Received::Event(e) => {
+                    if !self.accounts.contains(&e.account) || e.passwd.is_none() {
+                        continue;
+                    }
+                    if e.is_visible_with(&self.passwd) {
+                        return Some(Received::Event(e));
+                    }
+                    lockout::fail(&e.account, self.ip);
+                    self.accounts.remove(&e.account);
+                },
+                Received::Missed => return Some(Received::Missed),
+            }
+        }
+    }
+}
+
+/// Watch accounts by server-sent events.
+#[utoipa::path(
+    get, path = "/here/v1/client/watch", tag = "watching",
+    params(WatchParams, ("Last-Event-ID" = Option<String>, Header, description = "The same as `cursor`.")),
+    responses(
+        (
+            status = 200, content_type = "text/event-stream",
+            description = "The `changed`, `renewed` and `expired` events of the accounts as JSON, with their ids. A `missed` event tells that some events were lost.",
+        ),
+        (status = 403, description = "No password is given, or it matches none of the recorded accounts.", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Locked out after wrong passwords for an account or from the address.", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds until a password may be tried again."))),
+    )
+)]
+pub(crate) async fn watch_client_info_sse(
+    Extension(events): Extension<Arc<EventHub>>,
+    Extension(ClientAddr(client_addr)): Extension<ClientAddr>,
+    Query(params): Query<WatchParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let watch = Watch::new(&events, params, &headers, client_addr)?;
+    let stream = futures::stream::unfold(watch, |mut watch| async move {
+        let sse_event = match watch.next().await? {
+            Received::Event(e) => Event::default()
+                .id(e.id.to_string())
+                .event(e.event.name())
+                .json_data(&e)
+                .expect("Cannot serialize the event."),
+            Received::Missed => Event::default().event(MISSED_EVENT_NAME).data(""),
+        };
+        Some((Ok::<_, Infallible>(sse_event), watch))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Watch accounts by a WebSocket. Each event is sent as a JSON text message.
+#[utoipa::path(
+    get, path = "/here/v1/client/watch/ws", tag = "watching",
+    params(WatchParams),
+    responses(
+        (status = 101, description = "Upgraded to a WebSocket, which sends the events of the accounts as JSON text messages."),
+        (status = 403, description = "No password is given, or it matches none of the recorded accounts.", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Locked out after wrong passwords for an account or from the address.", body = Problem, content_type = "application/problem+json", headers(("Retry-After" = u64, description = "Seconds until a password may be tried again."))),
+    )
+)]
+pub(crate) async fn watch_client_info_ws(
+    ws: WebSocketUpgrade,
+    Extension(events): Extension<Arc<EventHub>>,
+    Extension(ClientAddr(client_addr)): Extension<ClientAddr>,
+    Query(params): Query<WatchParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let watch = Watch::new(&events, params, &headers, client_addr)?;
+    Ok(ws.on_upgrade(|socket| forward_to_socket(socket, watch)))
+}
+
+/// Send the events to the socket until either side closes.
+async fn forward_to_socket(mut socket: WebSocket, mut watch: Watch) {
+    loop {
+        tokio::select! {
+            received = watch.next() => {
+                let text = match received {
+                    Some(Received::Event(e)) => serde_json::to_string(&e).expect("Cannot serialize the event."),
+                    Some(Received::Missed) => format!(r#"{{"event":"{}"}}"#, MISSED_EVENT_NAME),
+                    None => break,
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            },
+            message = socket.recv() => {
+                /* Nothing is expected from the consumer, except closing. */
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde_derive::{Serialize, Deserialize};
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+use utils::logging::Redacted;
+
+use crate::events::ClientEvent;
+use crate::persist::write_atomically;
+
+/// The path where the webhook outbox file put.
+pub(crate) const WEBHOOK_OUTBOX_PATH: &str = "./webhook-outbox.json";
+
+/// The header carrying the HMAC-SHA256 signature of the body.
+const SIGNATURE_HEADER: &str = "X-Here-Signature";
+
+/// The header carrying the event kind.
+const EVENT_HEADER: &str = "X-Here-Event";
+
+/// Delay before the first retry. Seconds.
+const RETRY_BASE_DELAY: u64 = 1;
+
+/// The longest delay between two retries. Seconds.
+const RETRY_MAX_DELAY: u64 = 600;
+
+/// Give up an event after so many failed deliveries.
+const MAX_ATTEMPTS: u32 = 12;
+
+/// Timeout of a single delivery. Seconds.
+const DELIVERY_TIMEOUT: u64 = 10;
+
+/// Wake up at least this often even if nothing is notified. Seconds.
+const IDLE_DELAY: u64 = 60;
+
+/// A webhook target in the server config.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: String,
+    /// The key to sign the body with. Unsigned if `None`.
+    pub(crate) secret: Option<String>,
+}
+
+impl std::fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url)
+            .field("secret", &Redacted(&self.secret))
+            .finish()
+    }
+}
+
+/// An event waiting to be delivered to one target.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+struct OutboxEntry {
+    id: u64,
+    url: String,
+    event: String,
+    body: String,
+    attempts: u32,
+    /// Unix timestamp in milliseconds.
+    next_attempt: i64,
+}
+
+/// Where the changed outbox is sent to be written, and the thread writing it.
+struct OutboxWriter {
+    sender: mpsc::Sender<Vec<OutboxEntry>>,
+    writing: JoinHandle<()>,
+}
+
+/// Delivers events to the webhook targets. Events wait in a persisted outbox
+/// until delivered, so they survive a server restart.
+pub(crate) struct WebhookDispatcher {
+    /// Replaced when the config is reloaded.
+    targets: RwLock<Vec<WebhookConfig>>,
+    outbox: Mutex<Vec<OutboxEntry>>,
+    /// Taken when closing.
+    writer: Mutex<Option<OutboxWriter>>,
+    notify: Notify,
+}
+
+impl WebhookDispatcher {
+    /// Open (or create) the outbox at `outbox_path`, and start the thread writing it.
+    pub(crate) fn new(targets: Vec<WebhookConfig>, outbox_path: &str) -> Result<Self, anyhow::Error> {
+        let outbox_path = PathBuf::from(outbox_path);
+        let outbox = match fs::read(&outbox_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| anyhow::anyhow!("Cannot read the webhook outbox: {}", e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => anyhow::bail!("Cannot open the webhook outbox: {}", e),
+        };
+        let (sender, changes) = mpsc::channel();
+        let writing = thread::Builder::new()
+            .name("webhook-outbox".to_owned())
+            .spawn(move || write_outbox(&outbox_path, changes))?;
+        Ok(Self {
+            targets: RwLock::new(targets),
+            outbox: Mutex::new(outbox),
+            writer: Mutex::new(Some(OutboxWriter { sender, writing })),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Deliver the new events to `targets`. The waiting entries of the removed targets are dropped.
+    pub(crate) fn set_targets(&self, targets: Vec<WebhookConfig>) {
+        *self.targets.write().expect("Webhook targets lock poisoned.") = targets;
+        self.notify.notify_one();
+    }
+
+    /// Put an event into the outbox, one entry for each target.
+    pub(crate) fn enqueue(&self, event: &ClientEvent) {
+        let targets = self.targets.read().expect("Webhook targets lock poisoned.").clone();
+        if targets.is_empty() {
+            return;
+        }
+        let body = match serde_json::to_string(event) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Cannot serialize the event: {}", e);
+                return;
+            },
+        };
+        let event_name = event.event.name().to_owned();
+        let now = Utc::now().timestamp_millis();
+        {
+            let mut outbox = self.outbox.lock().expect("Webhook outbox lock poisoned.");
+            let first_id = outbox.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+            for (id, target) in (first_id..).zip(&targets) {
+                let entry = OutboxEntry {
+                    id,
+                    url: target.url.clone(),
+                    event: event_name.clone(),
+                    body: body.clone(),
+                    attempts: 0,
+                    next_attempt: now,
+                };
+                outbox.push(entry);
+            }
+            self.persist(&outbox);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Wait for the outbox to be written, and write it no more, so the process can exit
+    /// with the outbox file whole. The changes after are only kept in memory.
+    pub(crate) fn close(&self) {
+        let writer = self.writer.lock().expect("Webhook outbox writer lock poisoned.").take();
+        if let Some(OutboxWriter { sender, writing }) = writer {
+            /* The thread writes what was sent, and ends. */
+            drop(sender);
+            writing.join().expect("Thread writing the webhook outbox joining error.");
+        }
+    }
+
+    /// Hand the changed outbox to the writing thread, in the order of the changes,
+    /// so the async workers never wait for the disk.
+    fn persist(&self, outbox: &[OutboxEntry]) {
+        if let Some(writer) = &*self.writer.lock().expect("Webhook outbox writer lock poisoned.") {
+            let _ = writer.sender.send(outbox.to_vec());
+        }
+    }
+
+    /// Deliver the outbox forever.
+    pub(crate) async fn run(&self) {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(DELIVERY_TIMEOUT))
+            .build()
+            .expect("Cannot build the webhook HTTP client.");
+        loop {
+            for entry in self.due_entries() {
+                /* The secret is not persisted, take it from the config. */
+                let target = self.targets.read().expect("Webhook targets lock poisoned.")
+                    .iter().find(|t| t.url == entry.url).cloned();
+                match target {
+                    Some(target) => {
+                        let result = deliver(&client, &entry, &target.secret).await;
+                        self.finish(entry, result);
+                    },
+                    None => self.drop_entry(entry),
+                }
+            }
+            /* Sleep until the next entry is due, or a new event comes. */
+            let wait = self.next_wait();
+            tokio::select! {
+                _ = self.notify.notified() => {},
+                _ = tokio::time::sleep(wait) => {},
+            }
+        }
+    }
+
+    /// Entries which should be tried now.
+    fn due_entries(&self) -> Vec<OutboxEntry> {
+        let now = Utc::now().timestamp_millis();
+        let outbox = self.outbox.lock().expect("Webhook outbox lock poisoned.");
+        outbox.iter().filter(|e| e.next_attempt <= now).cloned().collect()
+    }
+
+    /// How long until the earliest entry is due.
+    fn next_wait(&self) -> Duration {
+        let now = Utc::now().timestamp_millis();
+        let outbox = self.outbox.lock().expect("Webhook outbox lock poisoned.");
+        match outbox.iter().map(|e| e.next_attempt).min() {
+            Some(at) => Duration::from_millis((at - now).max(0) as u64),
+            None => Duration::from_secs(IDLE_DELAY),
+        }
+    }
+
+    /// Remove an entry whose target is no longer configured.
+    fn drop_entry(&self, entry: OutboxEntry) {
+        warn!(event = %entry.event, url = %entry.url, "Drop the event: the target is no longer configured.");
+        let mut outbox = self.outbox.lock().expect("Webhook outbox lock poisoned.");
+        outbox.retain(|e| e.id != entry.id);
+        self.persist(&outbox);
+    }
+
+    /// Remove a delivered entry, or schedule a retry of a failed one.
+    fn finish(&self, entry: OutboxEntry, result: Result<(), anyhow::Error>) {
+        let mut outbox = self.outbox.lock().expect("Webhook outbox lock poisoned.");
+        match result {
+            Ok(_) => {
+                debug!(event = %entry.event, url = %entry.url, "Delivered the event.");
+                outbox.retain(|e| e.id != entry.id);
+            },
+            Err(e) => {
+                let attempts = entry.attempts + 1;
+                if attempts >= MAX_ATTEMPTS {
+                    error!(event = %entry.event, url = %entry.url, attempts, "Give up the event: {}", e);
+                    outbox.retain(|waiting| waiting.id != entry.id);
+                }
+                else {
+                    warn!(event = %entry.event, url = %entry.url, attempts, "Cannot deliver the event: {}", e);
+                    let next_attempt = Utc::now().timestamp_millis() + retry_delay(attempts).as_millis() as i64;
+                    if let Some(waiting) = outbox.iter_mut().find(|e| e.id == entry.id) {
+                        *waiting = OutboxEntry { attempts, next_attempt, ..entry };
+                    }
+                }
+            },
+        }
+        self.persist(&outbox);
+    }
+}
+
+/// Post an entry to its target.
+async fn deliver(client: &reqwest::Client, entry: &OutboxEntry, secret: &Option<String>) -> Result<(), anyhow::Error> {
+    let mut request = client.post(&entry.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, &entry.event);
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &entry.body)));
+    }
+    let resp = request.body(entry.body.clone()).send().await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("the target responded {}", resp.status());
+    }
+    Ok(())
+}
+
+/// Exponential backoff: the base delay doubled for each attempt, up to the max delay.
+fn retry_delay(attempts: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << attempts.saturating_sub(1).min(32));
+    Duration::from_secs(delay.min(RETRY_MAX_DELAY))
+}
+
+/// The hex HMAC-SHA256 of `body` with key `secret`.
+pub(crate) fn sign(secret: &str, body: &str) -> String {
+    use crypto::hmac::Hmac;
+    use crypto::mac::Mac;
+    use crypto::sha2::Sha256;
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body.as_bytes());
+    hmac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write the outboxes sent by `changes` down to the file, until the sender is dropped.
+/// Only the latest of the waiting ones is written. The events are still in memory if it fails.
+fn write_outbox(path: &Path, changes: mpsc::Receiver<Vec<OutboxEntry>>) {
+    while let Ok(mut outbox) = changes.recv() {
+        while let Ok(newer) = changes.try_recv() {
+            outbox = newer;
+        }
+        let written = serde_json::to_vec(&outbox).map_err(io::Error::from)
+            .and_then(|bytes| write_atomically(path, &bytes));
+        if let Err(e) = written {
+            error!("Cannot persist the webhook outbox: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(1));
+    assert_eq!(retry_delay(2), Duration::from_secs(2));
+    assert_eq!(retry_delay(5), Duration::from_secs(16));
+    assert_eq!(retry_delay(30), Duration::from_secs(RETRY_MAX_DELAY));
+}
+
+#[test]
+fn test_keep_outbox_across_restart() {
+    use crate::events::EventKind;
+    use crate::storage::ClientInfoRecord;
+    use utils::client::ClientInfo;
+    let outbox_path = std::env::temp_dir().join(format!("here-test-kept-outbox-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&outbox_path);
+    let target = WebhookConfig { url: "http://127.0.0.1:9/hook".to_owned(), secret: None };
+    let dispatcher = WebhookDispatcher::new(vec![target.clone()], outbox_path.to_str().unwrap()).unwrap();
+    let record = ClientInfoRecord::new(ClientInfo::new(1, "umoho"), 60);
+    dispatcher.enqueue(&ClientEvent::new(EventKind::Changed, &record));
+    dispatcher.enqueue(&ClientEvent::new(EventKind::Expired, &record));
+    dispatcher.close();
+
+    /* Not delivered before the restart, so both are still waiting. */
+    let dispatcher = WebhookDispatcher::new(vec![target], outbox_path.to_str().unwrap()).unwrap();
+    let events: Vec<String> = dispatcher.due_entries().into_iter().map(|e| e.event).collect();
+    assert_eq!(events, ["changed", "expired"]);
+    dispatcher.close();
+    let _ = std::fs::remove_file(&outbox_path);
+}
+
+#[tokio::test]
+async fn test_deliver_to_local_receiver() {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use axum::{Router, routing::post, http::HeaderMap, Extension};
+    use tokio::sync::mpsc;
+    use crate::events::{EventId, EventKind};
+
+    /* A local HTTP receiver, which passes what it received to the channel. */
+    let (tx, mut rx) = mpsc::unbounded_channel::<(HeaderMap, String)>();
+    let app = Router::new()
+        .route("/hook", post(|Extension(tx): Extension<mpsc::UnboundedSender<(HeaderMap, String)>>, headers: HeaderMap, body: String| async move {
+            tx.send((headers, body)).unwrap();
+        }))
+        .layer(Extension(tx));
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let outbox_path = std::env::temp_dir().join(format!("here-test-outbox-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&outbox_path);
+    let target = WebhookConfig { url: format!("http://{}/hook", addr), secret: Some("s3cret".to_owned()) };
+    let dispatcher = Arc::new(WebhookDispatcher::new(vec![target], outbox_path.to_str().unwrap()).unwrap());
+    tokio::spawn({
+        let dispatcher = dispatcher.clone();
+        async move { dispatcher.run().await }
+    });
+
+    let event = ClientEvent {
+        id: EventId { epoch: 1, seq: 1 },
+        event: EventKind::Changed,
+        account: "umoho".to_owned(),
+        ipv4s: vec![Ipv4Addr::new(192, 0, 2, 1)],
+        ipv6s: vec![],
+        observed: None,
+        time: Utc::now(),
+        passwd: None,
+    };
+    dispatcher.enqueue(&event);
+
+    let (headers, body) = tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(headers[EVENT_HEADER], "changed");
+    assert_eq!(headers[SIGNATURE_HEADER], format!("sha256={}", sign("s3cret", &body)));
+    let received: ClientEvent = serde_json::from_str(&body).unwrap();
+    assert_eq!((received.event, received.account, received.ipv4s), (event.event, event.account, event.ipv4s));
+    let _ = std::fs::remove_file(&outbox_path);
+}
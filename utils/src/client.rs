@@ -1,13 +1,22 @@
+use std::fmt::Debug;
 use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
 
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone, Debug)]
+use crate::logging::Redacted;
+
+/// The longest account name.
+pub const MAX_ACCOUNT_LENGTH: usize = 64;
+
+#[derive(Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct ClientInfo {
     pub id: u128,
     pub account: String,
     pub passwd: Option<String>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>, format = Ipv4))]
     pub ipv4s: Vec<Ipv4Addr>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>, format = Ipv6))]
     pub ipv6s: Vec<Ipv6Addr>,
 }
 
@@ -27,7 +36,7 @@ impl ClientInfo {
         /* Get the sha256 of the password if the password exists. */
         let passwd_sha256 = match passwd_plaintext {
             Some(plaintext) => {
-                 let sha256ed = sha256(&plaintext);
+                 let sha256ed = sha256(plaintext);
                  Some(sha256ed)
             },
             None => None,
@@ -52,15 +61,36 @@ impl ClientInfo {
     /// and the password hash of self, return true if same.
     pub fn verify_passwd(&self, passwd_plaintext: &str) -> bool {
         match &self.passwd {
-            Some(p) => {
-                let passwd_sha256ed = sha256(passwd_plaintext);
-                if p == &passwd_sha256ed { true } else { false }
-            },
+            Some(p) => verify_passwd_hash(p, passwd_plaintext),
             None => false,
         }
     }
 }
 
+impl Debug for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientInfo")
+            .field("id", &self.id)
+            .field("account", &self.account)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("ipv4s", &self.ipv4s)
+            .field("ipv6s", &self.ipv6s)
+            .finish()
+    }
+}
+
+/// Whether an account name is valid: 1 to `MAX_ACCOUNT_LENGTH` ASCII letters, digits, `-`, `_`, `.` or `@`.
+pub fn is_valid_account(account: &str) -> bool {
+    !account.is_empty() && account.len() <= MAX_ACCOUNT_LENGTH
+        && account.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c))
+}
+
+/// Verify the hash between the password plaintext and a password hash, such as the one
+/// of a record, return true if same.
+pub fn verify_passwd_hash(passwd_hash: &str, passwd_plaintext: &str) -> bool {
+    passwd_hash == sha256(passwd_plaintext)
+}
+
 /// A simple function for get an sha256ed hash from a plaintext.
 fn sha256(plaintext: &str) -> String {
     use crypto::sha2::Sha256;
@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use toml::{Value, value::Table};
+
+/// The prefix of the environment variables.
+const ENV_PREFIX: &str = "HERE_";
+
+/// Separates the keys of nested tables in the environment variable names.
+/// Example: `HERE_WIREGUARD__INTERFACE` is `interface` in the `[wireguard]` table.
+const ENV_NESTING: &str = "__";
+
+/// The system-wide config directory.
+const SYSTEM_CONFIG_DIR: &str = "/etc/here";
+
+/// A string field of the config. It can be asked for at the cmdline.
+pub struct Field {
+    pub key: &'static str,
+    pub prompt: &'static str,
+    pub required: bool,
+}
+
+/// Checks a config after it is deserialized.
+pub trait Validate {
+    /// The top-level keys of the config. Other keys in the config files are reported as unknown.
+    const KEYS: &'static [&'static str];
+
+    /// Push an error for every invalid field.
+    fn validate(&self, errors: &mut Vec<FieldError>);
+}
+
+/// A field with an invalid value.
+#[derive(Debug)]
+pub struct FieldError {
+    /// The path of the field, such as `bind`, `wireguard.interval` or `webhooks[0].url`.
+    pub key: String,
+    pub message: String,
+    /// How to fix it.
+    pub suggestion: Option<String>,
+}
+
+impl FieldError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+            suggestion: None,
+        }
+    }
+
+    pub fn suggest(mut self, suggestion: impl Into<String>) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+}
+
+/// Which layer a field is set by.
+#[derive(Debug)]
+pub enum Origin {
+    Default,
+    /// A config file, and the line and column of the value in it if found.
+    File(PathBuf, Option<(usize, usize)>),
+    Env(String),
+    Flag(String),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::File(path, Some((line, column))) => write!(f, "{}:{}:{}", path.display(), line, column),
+            Origin::File(path, None) => write!(f, "{}", path.display()),
+            Origin::Env(name) => write!(f, "environment variable {}", name),
+            Origin::Flag(flag) => write!(f, "cmdline flag {}", flag),
+        }
+    }
+}
+
+/// A field error, and where the field is set.
+#[derive(Debug)]
+pub struct Problem {
+    pub origin: Origin,
+    pub error: FieldError,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: `{}`: {}", self.origin, self.error.key, self.error.message)?;
+        if let Some(suggestion) = &self.error.suggestion {
+            write!(f, " ({})", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads a config from layers, each one overriding the ones before:
+/// built-in defaults, `/etc/here/<name>.toml`, `$XDG_CONFIG_HOME/here/<name>.toml`,
+/// the local config file, `HERE_*` environment variables, then the cmdline flags.
+pub struct ConfigLoader {
+    name: String,
+    local_path: PathBuf,
+    fields: Vec<Field>,
+    defaults: Table,
+    overrides: Table,
+}
+
+impl ConfigLoader {
+    /// `name` is the base name of the config files, `local_path` the local config file.
+    pub fn new(name: &str, local_path: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            local_path: PathBuf::from(local_path),
+            fields: vec![],
+            defaults: Table::new(),
+            overrides: Table::new(),
+        }
+    }
+
+    /// Declare a string field. Environment variables of it are never parsed as other types.
+    pub fn field(mut self, key: &'static str, prompt: &'static str, required: bool) -> Self {
+        self.fields.push(Field { key, prompt, required });
+        self
+    }
+
+    /// Set a built-in default value.
+    pub fn set_default(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.defaults.insert(key.to_owned(), value.into());
+        self
+    }
+
+    /// Set a value from the cmdline flags, if it is given.
+    pub fn set_override(mut self, key: &str, value: Option<impl Into<Value>>) -> Self {
+        if let Some(v) = value {
+            self.overrides.insert(key.to_owned(), v.into());
+        }
+        self
+    }
+
+    /// The config files, in the order they are applied.
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![Path::new(SYSTEM_CONFIG_DIR).join(format!("{}.toml", self.name))];
+        let xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(dir) = xdg_config_home {
+            paths.push(dir.join("here").join(format!("{}.toml", self.name)));
+        }
+        paths.push(self.local_path.clone());
+        paths
+    }
+
+    /// Merge all the layers into a table.
+    pub fn load_table(&self) -> Result<Table, ConfigError> {
+        let mut table = self.defaults.clone();
+        for path in self.file_paths() {
+            if let Some(file_table) = read_table(&path)? {
+                merge(&mut table, file_table);
+            }
+        }
+        merge(&mut table, env_table(std::env::vars(), &self.string_keys()));
+        merge(&mut table, self.overrides.clone());
+        Ok(table)
+    }
+
+    /// Load and validate the config. If some required fields are missing, ask for them at the cmdline
+    /// when it is a terminal (and save them to the local config file if it does not exist),
+    /// or return an error listing them otherwise.
+    pub fn load<T: DeserializeOwned + Validate>(&self) -> Result<T, ConfigError> {
+        self.load_checked(io::stdin().is_terminal())
+    }
+
+    /// Load and validate the config without asking for anything.
+    pub fn check<T: DeserializeOwned + Validate>(&self) -> Result<T, ConfigError> {
+        self.load_checked(false)
+    }
+
+    fn load_checked<T: DeserializeOwned + Validate>(&self, interactive: bool) -> Result<T, ConfigError> {
+        /* Report the typos first, they are often why a field is missing. */
+        let unknown = self.unknown_keys(T::KEYS)?;
+        if !unknown.is_empty() {
+            return Err(ConfigError::Rejected(unknown));
+        }
+        let mut table = self.load_table()?;
+        if !self.missing(&table).is_empty() {
+            if !interactive {
+                return Err(self.missing_error(&table));
+            }
+            let prompted = self.prompt_missing(&table)?;
+            if !self.local_path.exists() {
+                write_table(&self.local_path, &prompted)?;
+            }
+            merge(&mut table, prompted);
+        }
+        let config: T = Value::Table(table).try_into().map_err(ConfigError::Invalid)?;
+        let mut errors = vec![];
+        config.validate(&mut errors);
+        if !errors.is_empty() {
+            let problems = errors.into_iter()
+                .map(|error| Problem { origin: self.origin(&error.key), error })
+                .collect();
+            return Err(ConfigError::Rejected(problems));
+        }
+        Ok(config)
+    }
+
+    /// The config files which exist.
+    pub fn existing_files(&self) -> Vec<PathBuf> {
+        self.file_paths().into_iter().filter(|p| p.exists()).collect()
+    }
+
+    /// Which layer the field at `key` is set by. The last one wins.
+    pub fn origin(&self, key: &str) -> Origin {
+        if self.overrides.contains_key(key) {
+            return Origin::Flag(format!("--{}", key.replace('_', "-")));
+        }
+        if !key.contains('[') {
+            let name = format!("{}{}", ENV_PREFIX, key.replace('.', ENV_NESTING).to_uppercase());
+            if std::env::var_os(&name).is_some() {
+                return Origin::Env(name);
+            }
+        }
+        for path in self.file_paths().into_iter().rev() {
+            if let Ok(Some(table)) = read_table(&path) {
+                if lookup(&table, key).is_some() {
+                    let position = std::fs::read_to_string(&path).ok().and_then(|c| locate(&c, key));
+                    return Origin::File(path, position);
+                }
+            }
+        }
+        Origin::Default
+    }
+
+    /// The top-level keys in the config files which are not `known`.
+    fn unknown_keys(&self, known: &[&str]) -> Result<Vec<Problem>, ConfigError> {
+        let mut problems = vec![];
+        for path in self.file_paths() {
+            let table = match read_table(&path)? {
+                Some(t) => t,
+                None => continue,
+            };
+            for key in table.keys().filter(|k| !known.contains(&k.as_str())) {
+                let suggestion = match closest(key, known) {
+                    Some(k) => format!("did you mean `{}`?", k),
+                    None => format!("the known keys are {}", known.join(", ")),
+                };
+                let position = std::fs::read_to_string(&path).ok().and_then(|c| locate(&c, key));
+                problems.push(Problem {
+                    origin: Origin::File(path.clone(), position),
+                    error: FieldError::new(key, "unknown key").suggest(suggestion),
+                });
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Create the local config file from the environment variables, the cmdline flags
+    /// and input. The config files are ignored.
+    pub fn init(&self) -> Result<(), ConfigError> {
+        let mut table = env_table(std::env::vars(), &self.string_keys());
+        merge(&mut table, self.overrides.clone());
+        let prompted = self.prompt_missing(&table)?;
+        merge(&mut table, prompted);
+        write_table(&self.local_path, &table)
+    }
+
+    /// The required fields which are not set.
+    fn missing(&self, table: &Table) -> Vec<&Field> {
+        self.fields.iter().filter(|f| f.required && !table.contains_key(f.key)).collect()
+    }
+
+    fn missing_error(&self, table: &Table) -> ConfigError {
+        ConfigError::Missing {
+            keys: self.missing(table).iter().map(|f| f.key.to_owned()).collect(),
+            files: self.file_paths(),
+        }
+    }
+
+    /// Ask for every field not in `table` at the cmdline. Fail if it is not a terminal.
+    fn prompt_missing(&self, table: &Table) -> Result<Table, ConfigError> {
+        if !io::stdin().is_terminal() {
+            if self.missing(table).is_empty() {
+                return Ok(Table::new());
+            }
+            return Err(self.missing_error(table));
+        }
+        let mut prompted = Table::new();
+        for field in self.fields.iter().filter(|f| !table.contains_key(f.key)) {
+            print!("{}", field.prompt);
+            io::stdout().flush().map_err(ConfigError::Input)?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input).map_err(ConfigError::Input)?;
+            /* Leave an optional field blank to unset it. */
+            let input = input.trim();
+            if !input.is_empty() || field.required {
+                prompted.insert(field.key.to_owned(), Value::String(input.to_owned()));
+            }
+        }
+        Ok(prompted)
+    }
+
+    fn string_keys(&self) -> Vec<&str> {
+        self.fields.iter().map(|f| f.key).collect()
+    }
+}
+
+/// Why the config cannot be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Write(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Input(io::Error),
+    /// Required fields are not set, and nobody can be asked for them.
+    Missing { keys: Vec<String>, files: Vec<PathBuf> },
+    Invalid(toml::de::Error),
+    /// Some fields are set but invalid.
+    Rejected(Vec<Problem>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Cannot read the config file {}: {}", path.display(), e),
+            ConfigError::Write(path, e) => write!(f, "Cannot write the config file {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Cannot parse the config file {}: {}", path.display(), e),
+            ConfigError::Input(e) => write!(f, "Cannot read the config from the cmdline: {}", e),
+            ConfigError::Missing { keys, files } => {
+                let files: Vec<String> = files.iter().map(|p| p.display().to_string()).collect();
+                let vars: Vec<String> = keys.iter().map(|k| env_var_name(k)).collect();
+                let flags: Vec<String> = keys.iter().map(|k| format!("--{}", k.replace('_', "-"))).collect();
+                write!(
+                    f,
+                    "Missing config: {}. Set {} in a config file ({}), by the environment variables ({}), or by the cmdline flags ({}).",
+                    keys.join(", "), if keys.len() > 1 { "them" } else { "it" },
+                    files.join(", "), vars.join(", "), flags.join(", "),
+                )
+            },
+            ConfigError::Invalid(e) => write!(f, "Invalid config: {}", e),
+            ConfigError::Rejected(problems) => {
+                write!(f, "Invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The environment variable name of a top-level key.
+pub fn env_var_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+/// Check a `HOST:PORT` socket address, such as `127.0.0.1:8080` or `[::1]:8080`.
+pub fn check_socket_addr(key: &str, value: &str) -> Option<FieldError> {
+    match value.parse::<SocketAddr>() {
+        Ok(_) => None,
+        Err(_) => Some(
+            FieldError::new(key, format!("`{}` is not an IP address with a port", value))
+                .suggest("use IP:PORT, such as 127.0.0.1:8080 or [::]:8080")
+        ),
+    }
+}
+
+/// Check an `http` or `https` URL with a host.
+pub fn check_http_url(key: &str, value: &str) -> Option<FieldError> {
+    let suggestion = "use an URL such as http://localhost:8080/here";
+    match url::Url::parse(value) {
+        Ok(url) if url.scheme() != "http" && url.scheme() != "https" => Some(
+            FieldError::new(key, format!("the scheme `{}` is not supported", url.scheme()))
+                .suggest("use http:// or https://")
+        ),
+        Ok(url) if url.host_str().is_none_or(str::is_empty) => {
+            Some(FieldError::new(key, format!("`{}` has no host", value)).suggest(suggestion))
+        },
+        Ok(_) => None,
+        Err(e) => Some(FieldError::new(key, format!("`{}` is not an URL: {}", value, e)).suggest(suggestion)),
+    }
+}
+
+/// Check an account name. See `client::is_valid_account`.
+pub fn check_account(key: &str, value: &str) -> Option<FieldError> {
+    if crate::client::is_valid_account(value) {
+        return None;
+    }
+    Some(
+        FieldError::new(key, format!("`{}` is not a valid account", value))
+            .suggest(format!("use 1 to {} letters, digits, `-`, `_`, `.` or `@`", crate::client::MAX_ACCOUNT_LENGTH))
+    )
+}
+
+/// Check a number is in `range`.
+pub fn check_range(key: &str, value: u64, range: RangeInclusive<u64>) -> Option<FieldError> {
+    if range.contains(&value) {
+        return None;
+    }
+    Some(
+        FieldError::new(key, format!("{} is out of range", value))
+            .suggest(format!("use a value from {} to {}", range.start(), range.end()))
+    )
+}
+
+/// Check a file or directory exists.
+pub fn check_path_exists(key: &str, value: &str) -> Option<FieldError> {
+    if Path::new(value).exists() {
+        return None;
+    }
+    Some(FieldError::new(key, format!("`{}` does not exist", value)).suggest("check the path, or create the file"))
+}
+
+/// Read a config file into a table. `None` if the file does not exist.
+fn read_table(path: &Path) -> Result<Option<Table>, ConfigError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ConfigError::Read(path.to_owned(), e)),
+    };
+    toml::from_str(&contents).map(Some).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+}
+
+/// Write a table to a config file.
+fn write_table(path: &Path, table: &Table) -> Result<(), ConfigError> {
+    let contents = toml::to_string(table).expect("Cannot serialize the config.");
+    std::fs::write(path, contents).map_err(|e| ConfigError::Write(path.to_owned(), e))
+}
+
+/// Merge `other` into `table`. Tables are merged key by key, other values are replaced.
+fn merge(table: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(Value::Table(inner)), Value::Table(other_inner)) => merge(inner, other_inner),
+            (_, value) => {
+                table.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Build a table from the `HERE_*` environment variables. Values are parsed as TOML
+/// (numbers, booleans, arrays, quoted strings) when possible, except the `string_keys`.
+fn env_table(vars: impl Iterator<Item = (String, String)>, string_keys: &[&str]) -> Table {
+    let mut table = Table::new();
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(p) if !p.is_empty() => p.to_lowercase(),
+            _ => continue,
+        };
+        let keys: Vec<&str> = path.split(ENV_NESTING).collect();
+        let value = if string_keys.contains(&path.as_str()) {
+            Value::String(raw)
+        }
+        else {
+            match toml::from_str::<Table>(&format!("v = {}", raw)).ok().and_then(|mut t| t.remove("v")) {
+                Some(v) => v,
+                None => Value::String(raw),
+            }
+        };
+        /* Build the nested tables from the innermost key. */
+        let mut nested = value;
+        for key in keys[1..].iter().rev() {
+            let mut inner = Table::new();
+            inner.insert(key.to_string(), nested);
+            nested = Value::Table(inner);
+        }
+        merge(&mut table, Table::from_iter([(keys[0].to_owned(), nested)]));
+    }
+    table
+}
+
+/// Split a field path into the keys and the array indexes: `peers[1].port` is
+/// `[("peers", Some(1)), ("port", None)]`.
+fn segments(key: &str) -> Vec<(&str, Option<usize>)> {
+    key.split('.').map(|segment| {
+        match segment.strip_suffix(']').and_then(|s| s.split_once('[')) {
+            Some((name, index)) => (name, index.parse().ok()),
+            None => (segment, None),
+        }
+    }).collect()
+}
+
+/// The value at a field path in a table.
+fn lookup<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    let mut current: Option<&Value> = None;
+    for (name, index) in segments(key) {
+        let inner = match current {
+            None => table.get(name)?,
+            Some(value) => value.as_table()?.get(name)?,
+        };
+        current = Some(match index {
+            Some(i) => inner.as_array()?.get(i)?,
+            None => inner,
+        });
+    }
+    current
+}
+
+/// Find the line and the column (both from 1) of the value at a field path in a config file.
+/// Only the table headers and the `key = value` lines are understood, the inline tables are not.
+fn locate(contents: &str, key: &str) -> Option<(usize, usize)> {
+    let (table_path, leaf) = match key.rsplit_once('.') {
+        Some((table_path, leaf)) => (table_path, leaf),
+        None => ("", key),
+    };
+    let mut current = String::new();
+    /* How many times each array of tables is seen. */
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (n, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if let Some(header) = trimmed.strip_prefix("[[") {
+            let name = header.split("]]").next().unwrap_or_default().trim().to_owned();
+            let count = seen.entry(name.clone()).or_default();
+            current = format!("{}[{}]", name, count);
+            *count += 1;
+        }
+        else if let Some(header) = trimmed.strip_prefix('[') {
+            current = header.split(']').next().unwrap_or_default().trim().to_owned();
+        }
+        else {
+            match trimmed.split_once('=') {
+                Some((k, v)) if current == table_path && k.trim().trim_matches('"') == leaf => {
+                    return Some((n + 1, line.len() - v.trim_start().len() + 1));
+                },
+                _ => continue,
+            }
+        }
+        /* The key is a whole table. */
+        if current == key {
+            return Some((n + 1, indent + 1));
+        }
+    }
+    None
+}
+
+/// The closest `candidates` to a misspelled key, if it is close enough.
+fn closest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates.iter()
+        .map(|c| (edit_distance(key, c), *c))
+        .filter(|(d, _)| *d <= 2)
+        .min()
+        .map(|(_, c)| c)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[test]
+fn test_env_table() {
+    let vars = vec![
+        ("HERE_PASSWD".to_owned(), "123".to_owned()),
+        ("HERE_WIREGUARD__INTERVAL".to_owned(), "30".to_owned()),
+        ("HERE_WIREGUARD__INTERFACE".to_owned(), "wg0".to_owned()),
+        ("PATH".to_owned(), "/bin".to_owned()),
+    ];
+    let table = env_table(vars.into_iter(), &["passwd"]);
+    assert_eq!(table["passwd"], Value::String("123".to_owned()));
+    assert_eq!(table["wireguard"]["interval"], Value::Integer(30));
+    assert_eq!(table["wireguard"]["interface"], Value::String("wg0".to_owned()));
+    assert!(!table.contains_key("path"));
+}
+
+#[test]
+fn test_merge_layers() {
+    let mut table: Table = toml::from_str("bind = \"0.0.0.0:80\"\n[webhook]\nurl = \"a\"\nsecret = \"s\"").unwrap();
+    merge(&mut table, toml::from_str("[webhook]\nurl = \"b\"").unwrap());
+    assert_eq!(table["bind"].as_str(), Some("0.0.0.0:80"));
+    assert_eq!(table["webhook"]["url"].as_str(), Some("b"));
+    assert_eq!(table["webhook"]["secret"].as_str(), Some("s"));
+}
+
+#[test]
+fn test_locate_and_suggest() {
+    let contents = "bind = \"0.0.0.0:80\"\n\n[[webhooks]]\nurl = \"a\"\n[[webhooks]]\n  url = \"b\"\n[wireguard]\ninterval = 0\n";
+    assert_eq!(locate(contents, "bind"), Some((1, 8)));
+    assert_eq!(locate(contents, "webhooks[1].url"), Some((6, 9)));
+    assert_eq!(locate(contents, "webhooks[1]"), Some((5, 1)));
+    assert_eq!(locate(contents, "wireguard.interval"), Some((8, 12)));
+    assert_eq!(locate(contents, "api_url"), None);
+    let table: Table = toml::from_str(contents).unwrap();
+    assert_eq!(lookup(&table, "webhooks[1].url").and_then(Value::as_str), Some("b"));
+    assert_eq!(closest("api_ur", &["account", "passwd", "api_url"]), Some("api_url"));
+    assert_eq!(closest("wireguard", &["bind", "webhooks"]), None);
+}
@@ -1,11 +1,15 @@
 pub mod client;
+pub mod config;
+pub mod logging;
 pub mod server;
+pub mod version;
 
 use std::fmt::Display;
 
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct AppInfo {
     pub name: String,
     pub version: String,
@@ -0,0 +1,84 @@
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+/// Shown in place of a secret.
+pub const REDACTED: &str = "***";
+
+/// The query parameters which carry secrets.
+const SECRET_PARAMS: &[&str] = &["passwd", "password", "token", "secret"];
+
+/// The output formats of the logs.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format `{}`, use `text` or `json`", s)),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Start logging to stderr. `level` is a filter such as `info` or `warn,server=debug`.
+pub fn init(level: &str, format: LogFormat) -> Result<(), String> {
+    let filter = EnvFilter::try_new(level).map_err(|e| format!("Invalid log level `{}`: {}", level, e))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_current_span(true).with_span_list(false).try_init(),
+    };
+    result.map_err(|e| format!("Cannot start logging: {}", e))
+}
+
+/// Formats an optional secret without showing it.
+pub struct Redacted<'a>(pub &'a Option<String>);
+
+impl Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Some({:?})", REDACTED),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+/// Replace the values of the secret parameters in a query string.
+pub fn redact_query(query: &str) -> String {
+    query.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name.to_ascii_lowercase().as_str()) => {
+                format!("{}={}", name, REDACTED)
+            },
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[test]
+fn test_redact() {
+    assert_eq!(redact_query("account=umoho&passwd=123&wait=30"), "account=umoho&passwd=***&wait=30");
+    assert_eq!(redact_query("Token=abc&x"), "Token=***&x");
+    assert_eq!(format!("{:?}", Redacted(&Some("123".to_owned()))), r#"Some("***")"#);
+}
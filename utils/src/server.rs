@@ -1,16 +1,68 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::net::IpAddr;
+
 use serde_derive::{Serialize, Deserialize};
 
+use crate::AppInfo;
 use crate::client::ClientInfo;
+use crate::logging::Redacted;
 
 /// The param form of get client info requests.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams), into_params(parameter_in = Query))]
 pub struct GetClientInfoParams {
     pub account: String,
     pub passwd: Option<String>,
+    /// The version (ETag) of the record the caller has seen.
+    pub version: Option<String>,
+    /// Seconds to wait for the record to change from `version`.
+    pub wait: Option<u64>,
+}
+
+/// The param form of watch requests.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams), into_params(parameter_in = Query))]
+pub struct WatchParams {
+    /// The accounts to watch, separated by commas.
+    pub accounts: String,
+    /// The password of the accounts. Needed, and only the accounts it matches are watched.
+    pub passwd: Option<String>,
+    /// Resume after the event with this id, `<epoch>:<seq>`.
+    pub cursor: Option<String>,
+}
+
+impl Debug for GetClientInfoParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GetClientInfoParams")
+            .field("account", &self.account)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("version", &self.version)
+            .field("wait", &self.wait)
+            .finish()
+    }
+}
+
+impl Debug for WatchParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WatchParams")
+            .field("accounts", &self.accounts)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("cursor", &self.cursor)
+            .finish()
+    }
+}
+
+impl WatchParams {
+    /// The accounts to watch, without blanks.
+    pub fn accounts(&self) -> Vec<&str> {
+        self.accounts.split(',').map(str::trim).filter(|a| !a.is_empty()).collect()
+    }
 }
 
 /// The response form of get client info requests.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct GetClientInfoResponse {
     id: Option<u128>,
     account: String,
@@ -18,6 +70,28 @@ pub struct GetClientInfoResponse {
     is_ok: bool,
     message: Option<ResponseMessage>,
     data: Option<ClientInfo>,
+    /// The source address of the latest post, as the server observed it.
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    observed: Option<IpAddr>,
+    /// Whether the client is online, and when it was seen.
+    #[serde(default)]
+    presence: Option<Presence>,
+}
+
+impl Debug for GetClientInfoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GetClientInfoResponse")
+            .field("id", &self.id)
+            .field("account", &self.account)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("is_ok", &self.is_ok)
+            .field("message", &self.message)
+            .field("data", &self.data)
+            .field("observed", &self.observed)
+            .field("presence", &self.presence)
+            .finish()
+    }
 }
 
 impl GetClientInfoResponse {
@@ -29,6 +103,8 @@ impl GetClientInfoResponse {
             is_ok: false,
             message: None,
             data: None,
+            observed: None,
+            presence: None,
         }
     }
 
@@ -51,9 +127,80 @@ impl GetClientInfoResponse {
         self.data = Some(data);
         self
     }
+
+    pub fn set_observed(mut self, observed: Option<IpAddr>) -> Self {
+        self.observed = observed;
+        self
+    }
+
+    pub fn set_presence(mut self, presence: Presence) -> Self {
+        self.presence = Some(presence);
+        self
+    }
+
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.is_ok
+    }
+
+    pub fn message(&self) -> Option<&ResponseMessage> {
+        self.message.as_ref()
+    }
+
+    pub fn data(&self) -> Option<&ClientInfo> {
+        self.data.as_ref()
+    }
+
+    pub fn observed(&self) -> Option<IpAddr> {
+        self.observed
+    }
+
+    pub fn presence(&self) -> Option<&Presence> {
+        self.presence.as_ref()
+    }
+}
+
+/// The states a client passes through after its latest post.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum PresenceState {
+    /// Within the lifetime of the record.
+    Online,
+    /// The lifetime ran out, but still within the grace window.
+    Stale,
+    /// Beyond the grace window. The record is kept for the last seen time.
+    Offline,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Display for PresenceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Stale => write!(f, "stale"),
+            PresenceState::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// The presence of a client. Times are Unix timestamps in seconds.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Presence {
+    pub state: PresenceState,
+    /// When the latest post was recorded.
+    pub record_time: i64,
+    /// When the record runs out of its lifetime.
+    pub expiry: i64,
+    /// When the client was last seen, the same as `record_time`.
+    pub last_seen: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct PostClientInfoResponse {
     id: u128,
     account: String,
@@ -63,6 +210,19 @@ pub struct PostClientInfoResponse {
     lifetime: u64,
 }
 
+impl Debug for PostClientInfoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostClientInfoResponse")
+            .field("id", &self.id)
+            .field("account", &self.account)
+            .field("passwd", &Redacted(&self.passwd))
+            .field("is_ok", &self.is_ok)
+            .field("message", &self.message)
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
 impl PostClientInfoResponse {
     pub fn new(id: u128, account: &str, passwd: Option<String>) -> Self {
         Self {
@@ -93,12 +253,243 @@ impl PostClientInfoResponse {
     pub fn lifetime(&self) -> u64 {
         self.lifetime
     }
+
+    pub fn is_ok(&self) -> bool {
+        self.is_ok
+    }
+
+    pub fn message(&self) -> Option<&ResponseMessage> {
+        self.message.as_ref()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The machine readable codes of the errors.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum ResponseMessage {
     NotFound,
     AlreadyOccupiedId,
     InvalidPassword,
     DatabaseError,
-}
\ No newline at end of file
+    /// The server does not support the version of the client.
+    IncompatibleVersion,
+    /// The request cannot be read, or a field of it is invalid.
+    InvalidRequest,
+    /// The path does not take the method.
+    MethodNotAllowed,
+    /// Something went wrong in the server.
+    Internal,
+    /// The account or the address sent too many requests. Retry after the `Retry-After` header.
+    TooManyRequests,
+    /// Too many wrong passwords for the account, or from the address. Retry after the `Retry-After` header.
+    LockedOut,
+    /// The source address may not call the endpoint, or not for the account.
+    AddressNotAllowed,
+    /// A code of a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ResponseMessage {
+    /// A short summary, the same for every error with the code.
+    pub fn title(&self) -> &'static str {
+        match self {
+            ResponseMessage::NotFound => "Not found",
+            ResponseMessage::AlreadyOccupiedId => "The id is already occupied",
+            ResponseMessage::InvalidPassword => "Invalid password",
+            ResponseMessage::DatabaseError => "The records cannot be written",
+            ResponseMessage::IncompatibleVersion => "Incompatible client version",
+            ResponseMessage::InvalidRequest => "Invalid request",
+            ResponseMessage::MethodNotAllowed => "Method not allowed",
+            ResponseMessage::Internal => "Internal error",
+            ResponseMessage::TooManyRequests => "Too many requests",
+            ResponseMessage::LockedOut => "Locked out after wrong passwords",
+            ResponseMessage::AddressNotAllowed => "The address is not allowed",
+            ResponseMessage::Unknown => "Unknown error",
+        }
+    }
+}
+
+/// The media type of the problem details.
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// The prefix of the problem types. The code follows it.
+const PROBLEM_TYPE_PREFIX: &str = "urn:here:problem:";
+
+/// An error of any endpoint, as the problem details of RFC 7807.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Problem {
+    /// `urn:here:problem:` and the code.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// A short summary of the code.
+    pub title: String,
+    /// The HTTP status code.
+    pub status: u16,
+    /// What went wrong, for people.
+    pub detail: String,
+    /// The path of the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// What went wrong, for programs.
+    pub code: ResponseMessage,
+    /// The `X-Request-Id` of the request, to find it in the logs of the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The invalid fields of the request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldProblem>,
+}
+
+impl Problem {
+    pub fn new(code: ResponseMessage, status: u16, detail: &str) -> Self {
+        Self {
+            problem_type: format!("{}{:?}", PROBLEM_TYPE_PREFIX, code),
+            title: code.title().to_owned(),
+            status,
+            detail: detail.to_owned(),
+            instance: None,
+            code,
+            request_id: None,
+            errors: vec![],
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} ({:?}, status {}", self.title, self.detail, self.code, self.status)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, ", request id {}", request_id)?;
+        }
+        write!(f, ")")?;
+        for error in &self.errors {
+            write!(f, "\n  `{}`: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// An invalid field of a request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FieldProblem {
+    /// Such as `ipv4s[2]`.
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldProblem {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { field: field.into(), message: message.into() }
+    }
+}
+
+/// The discovery document at the API root: the server, and the API versions it offers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Discovery {
+    pub app: AppInfo,
+    /// The newest first.
+    pub versions: Vec<ApiVersion>,
+}
+
+impl Discovery {
+    /// The newest version offered which is in `supported`.
+    pub fn pick(&self, supported: &[&str]) -> Option<&ApiVersion> {
+        self.versions.iter().find(|v| supported.contains(&v.version.as_str()))
+    }
+}
+
+/// An API version offered by the server.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiVersion {
+    /// Such as `v1`.
+    pub version: String,
+    /// The paths of the endpoints, relative to the API root.
+    pub endpoints: BTreeMap<Endpoint, String>,
+    pub capabilities: Vec<Capability>,
+}
+
+impl ApiVersion {
+    /// The path of an endpoint relative to the API root, if this version has it.
+    pub fn endpoint(&self, endpoint: Endpoint) -> Option<&str> {
+        self.endpoints.get(&endpoint).map(String::as_str)
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// The endpoints of an API version.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum Endpoint {
+    ServerInfo,
+    GetClientInfo,
+    PostClientInfo,
+    DeregisterClientInfo,
+    /// Server-sent events.
+    Watch,
+    #[serde(rename = "watch_ws")]
+    WatchWebSocket,
+    /// The OpenAPI document.
+    #[serde(rename = "openapi")]
+    OpenApi,
+    /// An endpoint of a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The features of an API version, beyond posting and getting the records.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum Capability {
+    /// The presence states in the get responses.
+    Presence,
+    /// The source address the server observed, in the get responses.
+    ObservedAddress,
+    /// Taking a record offline at once.
+    Deregister,
+    /// Waiting on a get request for the record to change.
+    LongPoll,
+    /// Watching the events by server-sent events or a WebSocket.
+    Streaming,
+    /// A capability of a newer server.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Presence => write!(f, "presence"),
+            Capability::ObservedAddress => write!(f, "observed_address"),
+            Capability::Deregister => write!(f, "deregister"),
+            Capability::LongPoll => write!(f, "long_poll"),
+            Capability::Streaming => write!(f, "streaming"),
+            Capability::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[test]
+fn test_discovery_from_newer_server() {
+    let discovery: Discovery = serde_json::from_str(r#"{
+        "app": {"name": "here-server", "version": "9.0.0"},
+        "versions": [
+            {"version": "v9", "endpoints": {"teleport": "v9/teleport"}, "capabilities": ["teleport"]},
+            {"version": "v1", "endpoints": {"server_info": "v1/server", "history": "v1/history"}, "capabilities": ["deregister", "history"]}
+        ]
+    }"#).unwrap();
+    let version = discovery.pick(&["v1"]).unwrap();
+    assert_eq!(version.endpoint(Endpoint::ServerInfo), Some("v1/server"));
+    assert_eq!(version.endpoint(Endpoint::GetClientInfo), None);
+    assert!(version.supports(Capability::Deregister));
+    assert!(discovery.pick(&["v2"]).is_none());
+}
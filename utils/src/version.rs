@@ -0,0 +1,57 @@
+use std::cmp::Ordering;
+
+use semver::{Version, VersionReq};
+
+/// The header a client sends its version in.
+pub const CLIENT_VERSION_HEADER: &str = "x-here-client-version";
+
+/// The product of the client in its `User-Agent`, such as `here-client/0.1.0`.
+pub const CLIENT_PRODUCT: &str = "here-client";
+
+/// How the version of the other side fits ours.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Compatibility {
+    /// In the supported range, and not newer than ours.
+    Compatible,
+    /// In the supported range, but newer than ours. It may have features we do not know.
+    Newer,
+    /// Out of the supported range.
+    Incompatible,
+}
+
+/// Compare the version of the other side with ours and with the range we support,
+/// such as `^0.1`. Fails if a version or the range cannot be parsed.
+pub fn compare(ours: &str, theirs: &str, supported: &str) -> Result<Compatibility, String> {
+    let ours = Version::parse(ours).map_err(|e| format!("Invalid version `{}`: {}", ours, e))?;
+    let theirs = Version::parse(theirs).map_err(|e| format!("Invalid version `{}`: {}", theirs, e))?;
+    let supported = VersionReq::parse(supported).map_err(|e| format!("Invalid version range `{}`: {}", supported, e))?;
+    if !supported.matches(&theirs) {
+        return Ok(Compatibility::Incompatible);
+    }
+    match theirs.cmp_precedence(&ours) {
+        Ordering::Greater => Ok(Compatibility::Newer),
+        _ => Ok(Compatibility::Compatible),
+    }
+}
+
+/// The `User-Agent` of the client with `version`.
+pub fn client_user_agent(version: &str) -> String {
+    format!("{}/{}", CLIENT_PRODUCT, version)
+}
+
+/// The version of the client in a `User-Agent`, if it is the client.
+pub fn client_version_in_user_agent(user_agent: &str) -> Option<&str> {
+    user_agent.split_whitespace()
+        .find_map(|product| product.strip_prefix(CLIENT_PRODUCT)?.strip_prefix('/'))
+}
+
+#[test]
+fn test_compare() {
+    assert_eq!(compare("0.1.0", "0.1.0", "^0.1"), Ok(Compatibility::Compatible));
+    assert_eq!(compare("0.1.0", "0.1.5", "^0.1"), Ok(Compatibility::Newer));
+    assert_eq!(compare("0.1.5", "0.1.0", "^0.1"), Ok(Compatibility::Compatible));
+    assert_eq!(compare("0.1.0", "0.2.0", "^0.1"), Ok(Compatibility::Incompatible));
+    assert!(compare("0.1.0", "one", "^0.1").is_err());
+    assert_eq!(client_version_in_user_agent("here-client/0.1.0 reqwest"), Some("0.1.0"));
+    assert_eq!(client_version_in_user_agent("curl/8.0"), None);
+}